@@ -0,0 +1,218 @@
+/// Read-only access to previously exported metadata for the list, search and open commands
+use crate::op::Error;
+use crate::op7_metadata::{read_items, OP7ItemMetaData};
+use crate::privacy;
+use std::path::Path;
+use std::process::Command;
+
+/// Loads the exported items, limited to the accounts picked by the selectors when any are given,
+/// sorted the way they're printed.
+pub fn load(
+    export_path: &Path,
+    selectors: &[String],
+    strict: bool,
+) -> Result<Vec<OP7ItemMetaData>, Error> {
+    let expired = crate::manifest::load(export_path).expired_vaults(crate::manifest::now());
+    if !expired.is_empty() {
+        eprintln!(
+            "Warning: {} exported vaults have expired and may be out of date. Run `opbookmarks sync` to refresh them.",
+            expired.len()
+        );
+    }
+
+    let mut items = read_items(export_path);
+    if !selectors.is_empty() {
+        let account_ids = select_account_ids(&items, selectors, strict)?;
+        items.retain(|i| account_ids.contains(&i.profile_uuid));
+    }
+
+    items.sort_by(|a, b| {
+        (&a.profile_uuid, &a.vault_name, a.item_title.to_lowercase()).cmp(&(
+            &b.profile_uuid,
+            &b.vault_name,
+            b.item_title.to_lowercase(),
+        ))
+    });
+    Ok(items)
+}
+
+/// The IDs of the accounts picked by the selectors. Selectors that are all exported account IDs
+/// don't need `op`, anything else is resolved with `op` the way `sync` does.
+fn select_account_ids(
+    items: &[OP7ItemMetaData],
+    selectors: &[String],
+    strict: bool,
+) -> Result<Vec<String>, Error> {
+    let exported_id = |selector: &String| {
+        items
+            .iter()
+            .find(|i| i.profile_uuid.eq_ignore_ascii_case(selector))
+            .map(|i| i.profile_uuid.clone())
+    };
+    if let Some(account_ids) = selectors.iter().map(exported_id).collect() {
+        return Ok(account_ids);
+    }
+
+    let accounts = crate::op::load_all_accounts(selectors, strict)?;
+    Ok(accounts.into_iter().map(|a| a.id).collect())
+}
+
+pub fn list(items: &[OP7ItemMetaData], vault: Option<&str>) {
+    let items: Vec<&OP7ItemMetaData> = items
+        .iter()
+        .filter(|i| match vault {
+            Some(vault) => i.vault_uuid == vault || i.vault_name.eq_ignore_ascii_case(vault),
+            None => true,
+        })
+        .collect();
+
+    for item in items.iter() {
+        print_item(item);
+    }
+    println!("{} items", items.len());
+}
+
+/// Case insensitive match against titles and website URLs. Hashed titles only match the whole
+/// title. Returns false when hashed titles can't be looked up.
+pub fn search(items: &[OP7ItemMetaData], query: &str) -> bool {
+    let hashed_query = match hash_query(items, query) {
+        Ok(hashed_query) => hashed_query,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let query = query.to_lowercase();
+    let matches: Vec<&OP7ItemMetaData> = items
+        .iter()
+        .filter(|i| {
+            i.item_title.to_lowercase().contains(&query)
+                || hashed_query.as_deref() == Some(i.item_title.as_str())
+                || i.website_urls
+                    .iter()
+                    .any(|u| u.to_lowercase().contains(&query))
+        })
+        .collect();
+
+    for item in matches.iter() {
+        print_item(item);
+    }
+    println!("{} matching items", matches.len());
+    true
+}
+
+/// Opens the item with the given UUID or title in 1Password. Returns false when no single item
+/// could be identified.
+pub fn open(items: &[OP7ItemMetaData], query: &str, edit: bool) -> bool {
+    let item = match find(items, query) {
+        Ok(item) => item,
+        Err(candidates) => {
+            if candidates.is_empty() {
+                eprintln!("No exported item matches {:?}", query);
+            } else {
+                eprintln!("{:?} matches more than one item:", query);
+                for candidate in candidates {
+                    print_item(candidate);
+                }
+            }
+            return false;
+        }
+    };
+
+    let url = if edit {
+        item.edit_url()
+    } else {
+        item.view_url()
+    };
+
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+
+    println!("Opening {}", url);
+    match Command::new(opener).arg(&url).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            eprintln!("`{} {}` failed: {}", opener, url, status);
+            false
+        }
+        Err(e) => {
+            eprintln!("Unable to run `{}`: {}", opener, e);
+            false
+        }
+    }
+}
+
+/// An exact UUID match wins, followed by a case insensitive title match and finally a title
+/// substring. On failure the ambiguous candidates, if any, are returned.
+fn find<'a>(
+    items: &'a [OP7ItemMetaData],
+    query: &str,
+) -> Result<&'a OP7ItemMetaData, Vec<&'a OP7ItemMetaData>> {
+    if let Some(item) = items.iter().find(|i| i.uuid == query) {
+        return Ok(item);
+    }
+
+    let hashed_query = match hash_query(items, query) {
+        Ok(hashed_query) => hashed_query,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(vec![]);
+        }
+    };
+    let query = query.to_lowercase();
+    let exact: Vec<&OP7ItemMetaData> = items
+        .iter()
+        .filter(|i| {
+            i.item_title.to_lowercase() == query
+                || hashed_query.as_deref() == Some(i.item_title.as_str())
+        })
+        .collect();
+    let candidates = if exact.is_empty() {
+        items
+            .iter()
+            .filter(|i| i.item_title.to_lowercase().contains(&query))
+            .collect()
+    } else {
+        exact
+    };
+
+    if candidates.len() == 1 {
+        Ok(candidates[0])
+    } else {
+        Err(candidates)
+    }
+}
+
+/// The query hashed like titles exported with the `hashed` privacy level, if there are any. Fails
+/// rather than creating a key, as a new key couldn't match any exported title.
+fn hash_query(items: &[OP7ItemMetaData], query: &str) -> Result<Option<String>, String> {
+    if !items
+        .iter()
+        .any(|i| privacy::is_hashed_title(&i.item_title))
+    {
+        return Ok(None);
+    }
+
+    match privacy::existing_title_key() {
+        Ok(Some(key)) => Ok(Some(privacy::hash_title(query, &key))),
+        Ok(None) => Err(format!(
+            "Unable to look up hashed titles as {:?} is missing. Run `opbookmarks sync` to export them with a new key.",
+            privacy::title_key_path()
+        )),
+        Err(e) => Err(format!("Unable to read title hash key: {}", e)),
+    }
+}
+
+fn print_item(item: &OP7ItemMetaData) {
+    println!(
+        "{}  {}  {:<20}  {:<32}  {}",
+        item.profile_uuid,
+        item.uuid,
+        item.vault_name,
+        item.item_title,
+        item.website_urls.first().map(|u| u.as_str()).unwrap_or("")
+    );
+}
@@ -0,0 +1,511 @@
+/// Persists what was exported on the previous run so unchanged vaults can be skipped
+use crate::filter::{is_vault_exported, VaultFilter};
+use crate::op::VaultDetails;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The schema version written by this build. Bump it and add a step to `migrate` whenever the
+/// layout of `BookmarkCache` changes.
+pub const CACHE_VERSION: u64 = 2;
+
+const LEGACY_CACHE_FILE_NAME: &str = "cache.json";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BookmarkCache {
+    pub version: u64,
+
+    /// The export folder the cached versions describe. Guards against a hash collision in
+    /// `cache_path` as a cache for a different folder vouches for files that aren't there.
+    pub export_path: Option<PathBuf>,
+
+    pub vaults_by_account_id: HashMap<String, Vec<VaultDetails>>,
+
+    /// The version of every exported item by item ID, by vault ID, by account ID. Compared with
+    /// the next export to tell hooks which items were added, changed or removed.
+    pub item_versions: HashMap<String, HashMap<String, BTreeMap<String, usize>>>,
+
+    /// Set when the cache on disk was written by a newer version. This run works from an empty
+    /// cache that is never saved so the newer version finds its cache intact after an upgrade.
+    #[serde(skip)]
+    pub read_only: bool,
+}
+
+impl BookmarkCache {
+    pub fn new(export_path: &Path) -> BookmarkCache {
+        BookmarkCache {
+            version: CACHE_VERSION,
+            export_path: Some(export_path.to_path_buf()),
+            vaults_by_account_id: HashMap::new(),
+            item_versions: HashMap::new(),
+            read_only: false,
+        }
+    }
+
+    pub fn vault(&self, account_id: &str, vault_id: &str) -> Option<&VaultDetails> {
+        let vaults = self.vaults_by_account_id.get(account_id)?;
+        vaults.iter().find(|v| v.id.eq(vault_id))
+    }
+
+    pub fn set_item_versions(
+        &mut self,
+        account_id: &str,
+        vault_id: &str,
+        versions: BTreeMap<String, usize>,
+    ) {
+        self.item_versions
+            .entry(account_id.to_string())
+            .or_default()
+            .insert(vault_id.to_string(), versions);
+    }
+
+    /// Forgets the items of a vault whose files were removed
+    pub fn remove_item_versions(&mut self, account_id: &str, vault_id: &str) {
+        if let Some(vaults) = self.item_versions.get_mut(account_id) {
+            vaults.remove(vault_id);
+            if vaults.is_empty() {
+                self.item_versions.remove(account_id);
+            }
+        }
+    }
+
+    /// True when the vault's items or attributes, such as its name, changed since it was cached,
+    /// or it was never cached
+    pub fn has_changed(&self, account_id: &str, vault: &VaultDetails) -> bool {
+        match self.vault(account_id, &vault.id) {
+            Some(cached) => {
+                vault.content_version > cached.content_version
+                    || vault.attribute_version > cached.attribute_version
+            }
+            None => true,
+        }
+    }
+
+    /// Removes the matching vault entries so they're re-fetched on the next sync. Returns how many
+    /// vaults were removed.
+    pub fn invalidate(&mut self, account_id: Option<&str>, vault_id: Option<&str>) -> usize {
+        let mut count = 0;
+
+        for (id, vaults) in self.vaults_by_account_id.iter_mut() {
+            if matches!(account_id, Some(a) if a != id) {
+                continue;
+            }
+
+            let before = vaults.len();
+            vaults.retain(|v| matches!(vault_id, Some(vault_id) if v.id != vault_id));
+            count += before - vaults.len();
+        }
+
+        self.vaults_by_account_id
+            .retain(|_, vaults| !vaults.is_empty());
+        count
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Corrupt(String),
+    Unsupported(u64),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Corrupt(e) => write!(f, "cache is corrupt: {}", e),
+            Error::Unsupported(v) => write!(
+                f,
+                "cache version {} is newer than the supported version {}",
+                v, CACHE_VERSION
+            ),
+        }
+    }
+}
+
+/// The folder opbookmarks keeps its private state in: `$XDG_CACHE_HOME/opbookmarks`, falling
+/// back to `~/.cache/opbookmarks`.
+pub fn cache_dir() -> PathBuf {
+    let mut path = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut p = dirs::home_dir().unwrap();
+            p.push(".cache");
+            p
+        }
+    };
+    path.push("opbookmarks");
+    path
+}
+
+/// Each export path gets its own cache so profiles exporting to different folders don't
+/// invalidate each other.
+pub fn cache_path(export_path: &Path) -> PathBuf {
+    let mut path = cache_dir();
+    path.push(format!(
+        "cache-{:016x}.json",
+        crate::util::stable_hash(export_path.as_os_str().to_string_lossy().as_bytes())
+    ));
+    path
+}
+
+/// Where versions before the move to the cache folder stored the cache: next to the metadata.
+fn legacy_cache_path(export_path: &Path) -> PathBuf {
+    let mut path = export_path.to_path_buf();
+    path.push(LEGACY_CACHE_FILE_NAME);
+    path
+}
+
+pub fn load(export_path: &Path) -> BookmarkCache {
+    let mut path = cache_path(export_path);
+    if !path.exists() {
+        let legacy_path = legacy_cache_path(export_path);
+        if legacy_path.exists() {
+            println!("Migrating cache from {:?} to {:?}", legacy_path, path);
+            path = legacy_path;
+        }
+    }
+
+    read(&path, export_path)
+}
+
+fn read(path: &Path, export_path: &Path) -> BookmarkCache {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(_) => return BookmarkCache::new(export_path),
+    };
+
+    match parse(&json) {
+        Ok(mut cache) => {
+            if cache.export_path.is_none() {
+                cache.export_path = Some(export_path.to_path_buf());
+            }
+
+            if cache.export_path.as_deref() != Some(export_path) {
+                println!(
+                    "Ignoring cache for export path {:?} as {:?} is being exported to",
+                    cache.export_path, export_path
+                );
+                return BookmarkCache::new(export_path);
+            }
+            cache
+        }
+        Err(e @ Error::Unsupported(_)) => {
+            eprintln!(
+                "Not using {:?} and leaving it as it is because the {}",
+                path, e
+            );
+            let mut cache = BookmarkCache::new(export_path);
+            cache.read_only = true;
+            cache
+        }
+        Err(e) => {
+            eprintln!(
+                "Resetting caches because {:?} could not be loaded: {}",
+                path, e
+            );
+            backup_corrupt(path);
+            BookmarkCache::new(export_path)
+        }
+    }
+}
+
+pub fn save(cache: &BookmarkCache) {
+    if cache.read_only {
+        return;
+    }
+
+    let json = match serde_json::to_string(&cache) {
+        Ok(json) => json,
+        Err(err) => {
+            eprintln!("Error serializing json for cache: {}", err);
+            return;
+        }
+    };
+
+    let export_path = match &cache.export_path {
+        Some(export_path) => export_path,
+        None => return,
+    };
+
+    let path = cache_path(export_path);
+    if let Err(err) = crate::util::write_file(path.clone(), json) {
+        eprintln!("Error writing cache {:?}: {}", path, err);
+        return;
+    }
+
+    // The cache now lives in the cache folder; don't leave a stale copy behind for the next
+    // run to migrate again.
+    let legacy_path = legacy_cache_path(export_path);
+    if legacy_path.exists() {
+        if let Err(e) = std::fs::remove_file(&legacy_path) {
+            eprintln!("Unable to remove legacy cache {:?}: {}", legacy_path, e);
+        }
+    }
+}
+
+pub fn show(cache: &BookmarkCache) {
+    if let Some(export_path) = &cache.export_path {
+        println!(
+            "Cache: {:?} (version {})",
+            cache_path(export_path),
+            cache.version
+        );
+    }
+    println!("Export path: {:?}", cache.export_path);
+
+    if cache.vaults_by_account_id.is_empty() {
+        println!("No vaults cached.");
+        return;
+    }
+
+    let mut account_ids: Vec<&String> = cache.vaults_by_account_id.keys().collect();
+    account_ids.sort();
+
+    for account_id in account_ids {
+        println!("Account {}", account_id);
+
+        for vault in cache.vaults_by_account_id[account_id].iter() {
+            println!(
+                "  {} {:<24} content_version {:<6} attribute_version {}",
+                vault.id, vault.name, vault.content_version, vault.attribute_version
+            );
+        }
+    }
+}
+
+/// Compares every cached vault with what `op` currently reports. Returns false when the cache has
+/// drifted.
+pub fn verify(cache: &BookmarkCache, vault_filters: &[VaultFilter]) -> bool {
+    let mut in_sync = true;
+
+    for (account_id, vaults) in cache.vaults_by_account_id.iter() {
+        // Filters can name the account, so they need its details
+        let account = match crate::op::get_account(account_id) {
+            Ok(account) => account,
+            Err(e) => {
+                eprintln!("Unable to get account {}: {}", account_id, e);
+                in_sync = false;
+                continue;
+            }
+        };
+        let live_vaults = match crate::op::find_vaults(account_id) {
+            Ok(live_vaults) => live_vaults,
+            Err(e) => {
+                eprintln!("Unable to list vaults for account {}: {}", account_id, e);
+                in_sync = false;
+                continue;
+            }
+        };
+
+        for vault in vaults.iter() {
+            if !live_vaults.iter().any(|v| v.id == vault.id) {
+                println!("{}::{} no longer exists", account_id, vault.id);
+                in_sync = false;
+            }
+        }
+
+        for live_vault in live_vaults.iter() {
+            let live = match crate::op::get_vault(account_id, &live_vault.id) {
+                Ok(live) => live,
+                Err(e) => {
+                    eprintln!(
+                        "Unable to get vault {}::{}: {}",
+                        account_id, live_vault.id, e
+                    );
+                    in_sync = false;
+                    continue;
+                }
+            };
+
+            if !is_vault_exported(vault_filters, &account, &live) {
+                println!(
+                    "{}::{} is excluded by the vault filters",
+                    account_id, live.id
+                );
+                continue;
+            }
+
+            match vaults.iter().find(|v| v.id == live.id) {
+                None => {
+                    println!("{}::{} is not cached", account_id, live.id);
+                    in_sync = false;
+                }
+                Some(cached) => {
+                    if cached.content_version != live.content_version
+                        || cached.attribute_version != live.attribute_version
+                    {
+                        println!(
+                            "{}::{} is stale: cached content_version {} attribute_version {}, live content_version {} attribute_version {}",
+                            account_id,
+                            live.id,
+                            cached.content_version,
+                            cached.attribute_version,
+                            live.content_version,
+                            live.attribute_version
+                        );
+                        in_sync = false;
+                    } else {
+                        println!("{}::{} is up to date", account_id, live.id);
+                    }
+                }
+            }
+        }
+    }
+
+    in_sync
+}
+
+fn parse(json: &str) -> Result<BookmarkCache, Error> {
+    let value: Value = serde_json::from_str(json).map_err(|e| Error::Corrupt(e.to_string()))?;
+    let value = migrate(value)?;
+
+    serde_json::from_value(value).map_err(|e| Error::Corrupt(e.to_string()))
+}
+
+/// Upgrades a cache written by an older version one schema step at a time.
+fn migrate(mut value: Value) -> Result<Value, Error> {
+    loop {
+        let version = schema_version(&value)?;
+
+        value = match version {
+            CACHE_VERSION => return Ok(value),
+            0 => migrate_v0(value)?,
+            1 => migrate_v1(value)?,
+            v => return Err(Error::Unsupported(v)),
+        };
+    }
+}
+
+fn schema_version(value: &Value) -> Result<u64, Error> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| Error::Corrupt("expected a json object".to_string()))?;
+
+    match object.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| Error::Corrupt(format!("invalid version {}", version))),
+        // The original layout had no version field
+        None => Ok(0),
+    }
+}
+
+/// v0 was stored inside the export folder it described and had no `version` or `export_path`.
+/// The export path is filled in by `load` as the only place a v0 cache could have been found is
+/// the export path being loaded.
+fn migrate_v0(mut value: Value) -> Result<Value, Error> {
+    let object = value.as_object_mut().unwrap();
+    object.insert("version".to_string(), Value::from(1));
+    object.insert("export_path".to_string(), Value::Null);
+    Ok(value)
+}
+
+/// v2 added item versions. Every item of a vault exported by v1 is reported as added by its next
+/// export.
+fn migrate_v1(mut value: Value) -> Result<Value, Error> {
+    let object = value.as_object_mut().unwrap();
+    object.insert("version".to_string(), Value::from(2));
+    object.insert(
+        "item_versions".to_string(),
+        Value::Object(serde_json::Map::new()),
+    );
+    Ok(value)
+}
+
+fn backup_corrupt(path: &Path) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".corrupt-{}", timestamp));
+
+    match std::fs::rename(path, &backup) {
+        Ok(_) => eprintln!("Corrupt cache moved to {:?}", backup),
+        Err(e) => eprintln!("Unable to back up corrupt cache {:?}: {}", path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    /// A cache with one vault and the given fields before `vaults_by_account_id`
+    fn json(fields: &str) -> String {
+        format!(
+            r#"{{{}"vaults_by_account_id":{{"ACCT":[{{"id":"VAULT","name":"Private","attribute_version":1,"content_version":3,"type":"USER_CREATED","created_at":"","updated_at":""}}]}}}}"#,
+            fields
+        )
+    }
+
+    #[test]
+    fn older_caches_are_migrated_to_the_current_version() {
+        let v0 = parse(&json("")).unwrap();
+        assert_eq!(v0.version, CACHE_VERSION);
+        assert_eq!(v0.export_path, None);
+        assert_eq!(v0.vault("ACCT", "VAULT").unwrap().content_version, 3);
+        assert!(v0.item_versions.is_empty());
+
+        let v1 = parse(&json(r#""version":1,"export_path":"/bookmarks","#)).unwrap();
+        assert_eq!(v1.version, CACHE_VERSION);
+        assert_eq!(v1.export_path, Some(PathBuf::from("/bookmarks")));
+        assert!(v1.vault("ACCT", "VAULT").is_some());
+        assert!(v1.item_versions.is_empty());
+
+        assert!(matches!(
+            parse(r#"{"version":3,"vaults_by_account_id":{}}"#),
+            Err(Error::Unsupported(3))
+        ));
+        assert!(matches!(parse("[]"), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn v0_caches_take_the_export_path_they_were_found_for() {
+        let dir = TempDir::new("cache");
+        let path = dir.file("cache.json");
+        std::fs::write(&path, json("")).unwrap();
+
+        let cache = read(&path, Path::new("/bookmarks"));
+        assert_eq!(cache.export_path, Some(PathBuf::from("/bookmarks")));
+        assert!(cache.vault("ACCT", "VAULT").is_some());
+    }
+
+    #[test]
+    fn corrupt_caches_are_backed_up_and_reset() {
+        let dir = TempDir::new("cache");
+        let path = dir.file("cache.json");
+        std::fs::write(&path, "{not json").unwrap();
+
+        let cache = read(&path, Path::new("/bookmarks"));
+        assert!(cache.vaults_by_account_id.is_empty());
+        assert!(!cache.read_only);
+        assert!(!path.exists());
+
+        let backups: Vec<String> = std::fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].starts_with("cache.json.corrupt-"));
+        assert_eq!(
+            std::fs::read_to_string(dir.file(&backups[0])).unwrap(),
+            "{not json"
+        );
+    }
+
+    #[test]
+    fn caches_from_newer_versions_are_left_alone() {
+        let dir = TempDir::new("cache");
+        let path = dir.file("cache.json");
+        let newer = r#"{"version":3,"export_path":"/bookmarks"}"#;
+        std::fs::write(&path, newer).unwrap();
+
+        let cache = read(&path, Path::new("/bookmarks"));
+        assert!(cache.read_only);
+        assert!(cache.vaults_by_account_id.is_empty());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), newer);
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 1);
+    }
+}
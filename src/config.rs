@@ -0,0 +1,598 @@
+/// Settings from ~/.config/opbookmarks/config.toml, the environment and the command line
+use crate::encryption::{Encryption, EncryptionConfig, ExportFormat};
+use crate::filter::{ItemFilter, VaultFilter, VaultFilterConfig};
+use crate::gc::ExpiryAction;
+use crate::hooks::{Hook, HookConfig};
+use crate::lock::LockPolicy;
+use crate::privacy::{PrivacyLevel, PrivacyRule, PrivacyRuleConfig, PrivacySettings};
+use crate::resync::Schedule;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+const PROFILE_ENV: &str = "OPBOOKMARKS_PROFILE";
+const CONFIG_ENV: &str = "OPBOOKMARKS_CONFIG";
+const ACCOUNTS_ENV: &str = "OPBOOKMARKS_ACCOUNTS";
+const EXPORT_PATH_ENV: &str = "OPBOOKMARKS_EXPORT_PATH";
+const WATCH_PATH_ENV: &str = "OPBOOKMARKS_WATCH_PATH";
+const LOCK_TIMEOUT_ENV: &str = "OPBOOKMARKS_LOCK_TIMEOUT";
+const TTL_ENV: &str = "OPBOOKMARKS_TTL";
+
+/// The contents of config.toml
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The profile used when `--profile` isn't given
+    pub default_profile: Option<String>,
+
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub accounts: Option<Vec<String>>,
+    pub strict_accounts: Option<bool>,
+    /// Delete the exports of accounts that are signed out, removed or suspended
+    pub auto_purge: Option<bool>,
+    pub export_path: Option<PathBuf>,
+    pub lock_timeout: Option<u64>,
+    /// How long exported files can be trusted without a sync, such as `"7d"`
+    pub ttl: Option<String>,
+    /// What `opbookmarks gc` does with expired files
+    pub on_expiry: Option<ExpiryAction>,
+    pub vault_filters: Option<Vec<VaultFilterConfig>>,
+    pub item_filter: Option<ItemFilter>,
+    /// The privacy level of vaults not matched by any of the `privacy_rules`
+    pub privacy: Option<PrivacyLevel>,
+    pub privacy_rules: Option<Vec<PrivacyRuleConfig>>,
+    pub formats: Option<Vec<ExportFormat>>,
+    pub encryption: Option<EncryptionConfig>,
+    pub watch: Option<WatchProfile>,
+    pub serve: Option<ServeProfile>,
+    /// Commands run after each sync
+    pub hooks: Option<Vec<HookConfig>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchProfile {
+    pub path: Option<PathBuf>,
+    /// Database file names, or globs, whose writes trigger a sync
+    pub triggers: Option<Vec<String>>,
+    /// Check vault versions with `op` this often instead of watching the data folder, such as
+    /// `"5m"`
+    pub poll_interval: Option<String>,
+    /// Run a full resync that repairs the export folder at this time of day, such as `"03:00"`,
+    /// or this often, such as `"6h"`
+    pub resync: Option<String>,
+    /// Only read when the daemon starts
+    pub pid_file: Option<PathBuf>,
+    /// Only read when the daemon starts
+    pub control_socket: Option<PathBuf>,
+    /// Change events are written to this FIFO, created when missing. Only read when the daemon
+    /// starts.
+    pub events_fifo: Option<PathBuf>,
+    /// Change events are sent to every client of this Unix socket. Only read when the daemon
+    /// starts.
+    pub events_socket: Option<PathBuf>,
+}
+
+/// Only read when `serve` starts
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServeProfile {
+    pub port: Option<u16>,
+    pub token_file: Option<PathBuf>,
+}
+
+/// Values given on the command line. These win over everything else.
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub config_path: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub accounts: Vec<String>,
+    pub export_path: Option<PathBuf>,
+    pub lock_timeout: Option<u64>,
+    pub ttl: Option<u64>,
+    pub strict_accounts: bool,
+    pub auto_purge: bool,
+    pub include_vaults: Vec<String>,
+    pub exclude_vaults: Vec<String>,
+    pub include_categories: Vec<String>,
+    pub exclude_categories: Vec<String>,
+    pub include_tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+    pub require_urls: bool,
+    pub privacy: Option<PrivacyLevel>,
+    pub formats: Vec<ExportFormat>,
+    pub age_recipients: Vec<String>,
+    pub watch_path: Option<PathBuf>,
+    pub watch_triggers: Vec<String>,
+    pub poll_interval: Option<u64>,
+    pub resync: Option<Schedule>,
+    pub pid_file: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    /// Write change events to stdout
+    pub events_stdout: bool,
+    pub events_fifo: Option<PathBuf>,
+    pub events_socket: Option<PathBuf>,
+    pub serve_port: Option<u16>,
+    pub token_file: Option<PathBuf>,
+    /// Replace the configured hooks
+    pub hooks: Vec<String>,
+}
+
+/// The effective settings for this run
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Only set when given on the command line or in the environment
+    pub config_path: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub accounts: Vec<String>,
+    pub strict_accounts: bool,
+    pub auto_purge: bool,
+    pub export_path: PathBuf,
+    pub lock_policy: LockPolicy,
+    /// Seconds
+    pub ttl: Option<u64>,
+    pub on_expiry: ExpiryAction,
+    pub vault_filters: Vec<VaultFilter>,
+    pub item_filter: ItemFilter,
+    pub privacy: PrivacySettings,
+    pub formats: Vec<ExportFormat>,
+    /// Only set when encryption is configured or the `age` format is used
+    pub encryption: Option<Encryption>,
+    /// Resolved per platform by `watch` when not configured
+    pub watch_path: Option<PathBuf>,
+    pub watch_triggers: Vec<glob::Pattern>,
+    /// Seconds. Polls `op` instead of watching the data folder when set.
+    pub poll_interval: Option<u64>,
+    pub resync: Option<Schedule>,
+    pub pid_file: Option<PathBuf>,
+    pub control_socket: PathBuf,
+    pub events_stdout: bool,
+    pub events_fifo: Option<PathBuf>,
+    pub events_socket: Option<PathBuf>,
+    /// The port `serve` listens on at 127.0.0.1
+    pub serve_port: u16,
+    pub token_file: PathBuf,
+    pub hooks: Vec<Hook>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownProfile(String, Vec<String>),
+    InvalidEnv(&'static str, String),
+    InvalidFilter(String),
+    InvalidTtl(String),
+    InvalidPollInterval(String),
+    InvalidResync(String),
+    InvalidTrigger(String, glob::PatternError),
+    InvalidHook(String),
+    InvalidEncryption(crate::encryption::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Read(path, e) => write!(f, "unable to read {:?}: {}", path, e),
+            Error::Parse(path, e) => write!(f, "invalid configuration in {:?}: {}", path, e),
+            Error::UnknownProfile(name, available) => {
+                if available.is_empty() {
+                    write!(
+                        f,
+                        "profile {:?} not found, no profiles are configured",
+                        name
+                    )
+                } else {
+                    write!(
+                        f,
+                        "profile {:?} not found, available profiles are: {}",
+                        name,
+                        available.join(", ")
+                    )
+                }
+            }
+            Error::InvalidEnv(name, e) => write!(f, "invalid value for {}: {}", name, e),
+            Error::InvalidFilter(e) => write!(f, "invalid filter: {}", e),
+            Error::InvalidHook(e) => write!(f, "invalid hook: {}", e),
+            Error::InvalidTrigger(trigger, e) => {
+                write!(f, "invalid watch trigger {:?}: {}", trigger, e)
+            }
+            Error::InvalidTtl(e) => write!(f, "invalid ttl: {}", e),
+            Error::InvalidPollInterval(e) => write!(f, "invalid poll_interval: {}", e),
+            Error::InvalidResync(e) => write!(f, "invalid resync: {}", e),
+            Error::InvalidEncryption(e) => write!(f, "invalid encryption: {}", e),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/opbookmarks/config.toml`, falling back to `~/.config/opbookmarks/config.toml`
+pub fn default_config_path() -> PathBuf {
+    let mut path = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut p = dirs::home_dir().unwrap();
+            p.push(".config");
+            p
+        }
+    };
+    path.push("opbookmarks/config.toml");
+    path
+}
+
+pub fn default_export_path() -> PathBuf {
+    let mut path = dirs::home_dir().unwrap();
+    path.push(".config/op/bookmarks");
+    path
+}
+
+/// Loads the config file. A missing file is only an error when its path was given explicitly.
+pub fn load_file(path: Option<&Path>) -> Result<ConfigFile, Error> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => (default_config_path(), false),
+    };
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(ConfigFile::default())
+        }
+        Err(e) => return Err(Error::Read(path, e)),
+    };
+
+    toml::from_str(&contents).map_err(|e| Error::Parse(path, e))
+}
+
+/// Combines the command line, environment and config file, in that order of precedence.
+pub fn resolve(overrides: Overrides) -> Result<Settings, Error> {
+    let config_path = overrides.config_path.or_else(|| env_path(CONFIG_ENV));
+    let file = load_file(config_path.as_deref())?;
+
+    let profile_name = overrides
+        .profile
+        .or_else(|| env_string(PROFILE_ENV))
+        .or_else(|| file.default_profile.clone());
+
+    let profile = match &profile_name {
+        Some(name) => match file.profiles.get(name) {
+            Some(profile) => profile.clone(),
+            None => {
+                return Err(Error::UnknownProfile(
+                    name.clone(),
+                    file.profiles.keys().cloned().collect(),
+                ))
+            }
+        },
+        None => Profile::default(),
+    };
+
+    let accounts = if !overrides.accounts.is_empty() {
+        overrides.accounts
+    } else if let Some(accounts) = env_string(ACCOUNTS_ENV) {
+        accounts
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|a| !a.is_empty())
+            .map(|a| a.to_string())
+            .collect()
+    } else {
+        profile.accounts.unwrap_or_default()
+    };
+
+    let export_path = canonical_export_path(
+        &overrides
+            .export_path
+            .or_else(|| env_path(EXPORT_PATH_ENV))
+            .or_else(|| profile.export_path.map(|p| expand_home(&p)))
+            .unwrap_or_else(default_export_path),
+    );
+
+    let lock_timeout = match overrides.lock_timeout {
+        Some(timeout) => Some(timeout),
+        None => match env_string(LOCK_TIMEOUT_ENV) {
+            Some(timeout) => Some(
+                timeout
+                    .parse()
+                    .map_err(|_| Error::InvalidEnv(LOCK_TIMEOUT_ENV, timeout))?,
+            ),
+            None => profile.lock_timeout,
+        },
+    };
+
+    let ttl = match overrides.ttl {
+        Some(ttl) => Some(ttl),
+        None => match env_string(TTL_ENV) {
+            Some(ttl) => {
+                Some(crate::util::parse_duration(&ttl).map_err(|e| Error::InvalidEnv(TTL_ENV, e))?)
+            }
+            None => match &profile.ttl {
+                Some(ttl) => Some(crate::util::parse_duration(ttl).map_err(Error::InvalidTtl)?),
+                None => None,
+            },
+        },
+    };
+
+    // Filters given on the command line replace those in the config file
+    let vault_filter_configs =
+        if !overrides.include_vaults.is_empty() || !overrides.exclude_vaults.is_empty() {
+            vec![VaultFilterConfig {
+                account: None,
+                include: overrides.include_vaults,
+                exclude: overrides.exclude_vaults,
+            }]
+        } else {
+            profile.vault_filters.unwrap_or_default()
+        };
+    let vault_filters = vault_filter_configs
+        .iter()
+        .map(VaultFilter::parse)
+        .collect::<Result<Vec<VaultFilter>, String>>()
+        .map_err(Error::InvalidFilter)?;
+
+    // Item filter lists given on the command line replace the configured list
+    let mut item_filter = profile.item_filter.unwrap_or_default();
+    let replace = |configured: &mut Vec<String>, cli: Vec<String>| {
+        if !cli.is_empty() {
+            *configured = cli;
+        }
+    };
+    replace(
+        &mut item_filter.include_categories,
+        overrides.include_categories,
+    );
+    replace(
+        &mut item_filter.exclude_categories,
+        overrides.exclude_categories,
+    );
+    replace(&mut item_filter.include_tags, overrides.include_tags);
+    replace(&mut item_filter.exclude_tags, overrides.exclude_tags);
+    item_filter.require_urls |= overrides.require_urls;
+
+    // A privacy level given on the command line applies to every vault
+    let privacy = match overrides.privacy {
+        Some(level) => PrivacySettings {
+            default_level: level,
+            rules: vec![],
+        },
+        None => PrivacySettings {
+            default_level: profile.privacy.unwrap_or_default(),
+            rules: profile
+                .privacy_rules
+                .unwrap_or_default()
+                .iter()
+                .map(PrivacyRule::parse)
+                .collect::<Result<Vec<PrivacyRule>, String>>()
+                .map_err(Error::InvalidFilter)?,
+        },
+    };
+
+    let formats = if !overrides.formats.is_empty() {
+        overrides.formats
+    } else {
+        profile.formats.unwrap_or_else(|| vec![ExportFormat::Json])
+    };
+
+    let encryption_config = if !overrides.age_recipients.is_empty() {
+        Some(EncryptionConfig {
+            recipients: overrides.age_recipients,
+            passphrase_file: None,
+        })
+    } else {
+        profile.encryption
+    };
+    let encryption = match encryption_config {
+        Some(config) => Some(Encryption::from_config(&config).map_err(Error::InvalidEncryption)?),
+        None if formats.contains(&ExportFormat::Age) => Some(Encryption::PassphraseEnv),
+        None => None,
+    };
+    // Otherwise every vault would fail to export on its own
+    if let Some(encryption @ Encryption::PassphraseEnv) = &encryption {
+        if formats.contains(&ExportFormat::Age) {
+            encryption.passphrase().map_err(Error::InvalidEncryption)?;
+        }
+    }
+
+    let watch = profile.watch.unwrap_or_default();
+    let watch_path = overrides
+        .watch_path
+        .or_else(|| env_path(WATCH_PATH_ENV))
+        .or_else(|| watch.path.map(|p| expand_home(&p)));
+
+    let triggers = if !overrides.watch_triggers.is_empty() {
+        overrides.watch_triggers
+    } else {
+        watch.triggers.unwrap_or_else(|| {
+            crate::platform::DEFAULT_TRIGGERS
+                .iter()
+                .map(|t| t.to_string())
+                .collect()
+        })
+    };
+    let watch_triggers = triggers
+        .iter()
+        .map(|t| glob::Pattern::new(t).map_err(|e| Error::InvalidTrigger(t.clone(), e)))
+        .collect::<Result<Vec<glob::Pattern>, Error>>()?;
+
+    let poll_interval = match overrides.poll_interval {
+        Some(interval) => Some(interval),
+        None => match &watch.poll_interval {
+            Some(interval) => {
+                Some(crate::util::parse_duration(interval).map_err(Error::InvalidPollInterval)?)
+            }
+            None => None,
+        },
+    };
+
+    let resync = match overrides.resync {
+        Some(schedule) => Some(schedule),
+        None => match &watch.resync {
+            Some(schedule) => Some(Schedule::parse(schedule).map_err(Error::InvalidResync)?),
+            None => None,
+        },
+    };
+
+    let pid_file = overrides
+        .pid_file
+        .or_else(|| watch.pid_file.map(|p| expand_home(&p)));
+    let control_socket = overrides
+        .control_socket
+        .or_else(|| watch.control_socket.map(|p| expand_home(&p)))
+        .unwrap_or_else(|| crate::control::default_socket_path(&export_path));
+    let events_fifo = overrides
+        .events_fifo
+        .or_else(|| watch.events_fifo.map(|p| expand_home(&p)));
+    let events_socket = overrides
+        .events_socket
+        .or_else(|| watch.events_socket.map(|p| expand_home(&p)));
+
+    let serve = profile.serve.unwrap_or_default();
+    let serve_port = overrides
+        .serve_port
+        .or(serve.port)
+        .unwrap_or(crate::serve::DEFAULT_PORT);
+    let token_file = overrides
+        .token_file
+        .or_else(|| serve.token_file.map(|p| expand_home(&p)))
+        .unwrap_or_else(|| crate::serve::default_token_path(&export_path));
+
+    // Hooks given on the command line replace the configured ones
+    let hooks = if !overrides.hooks.is_empty() {
+        overrides.hooks.iter().map(|c| Hook::new(c)).collect()
+    } else {
+        profile
+            .hooks
+            .unwrap_or_default()
+            .iter()
+            .map(Hook::parse)
+            .collect::<Result<Vec<Hook>, String>>()
+            .map_err(Error::InvalidHook)?
+    };
+
+    Ok(Settings {
+        config_path,
+        profile: profile_name,
+        accounts,
+        strict_accounts: overrides.strict_accounts || profile.strict_accounts.unwrap_or(false),
+        auto_purge: overrides.auto_purge || profile.auto_purge.unwrap_or(false),
+        export_path,
+        lock_policy: LockPolicy::from_timeout(lock_timeout),
+        ttl,
+        on_expiry: profile.on_expiry.unwrap_or(ExpiryAction::Delete),
+        vault_filters,
+        item_filter,
+        privacy,
+        formats,
+        encryption,
+        watch_path,
+        watch_triggers,
+        poll_interval,
+        resync,
+        pid_file,
+        control_socket,
+        events_stdout: overrides.events_stdout,
+        events_fifo,
+        events_socket,
+        serve_port,
+        token_file,
+        hooks,
+    })
+}
+
+fn env_string(name: &str) -> Option<String> {
+    match std::env::var(name) {
+        Ok(value) if !value.is_empty() => Some(value),
+        _ => None,
+    }
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    env_string(name).map(|p| expand_home(Path::new(&p)))
+}
+
+/// The lock, cache, control socket and token are named after a hash of the export path, so every
+/// way of writing the same folder has to come out the same: absolute, without symlinks, `.` or
+/// `..`. The parts that don't exist yet are resolved as written.
+fn canonical_export_path(path: &Path) -> PathBuf {
+    let path = match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    let components: Vec<Component> = path.components().collect();
+    for existing in (1..=components.len()).rev() {
+        let prefix: PathBuf = components[..existing].iter().collect();
+        if let Ok(mut canonical) = prefix.canonicalize() {
+            for component in components[existing..].iter() {
+                match component {
+                    Component::ParentDir => {
+                        canonical.pop();
+                    }
+                    Component::Normal(name) => canonical.push(name),
+                    _ => {}
+                }
+            }
+            return canonical;
+        }
+    }
+    path
+}
+
+/// Paths in the config file and environment may start with `~/`
+pub fn expand_home(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => {
+            let mut p = dirs::home_dir().unwrap();
+            p.push(rest);
+            p
+        }
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn export_paths_are_the_same_however_theyre_written() {
+        let dir = TempDir::new("config");
+        let folder = dir.file("bookmarks");
+        std::fs::create_dir(&folder).unwrap();
+        std::os::unix::fs::symlink(&folder, dir.file("link")).unwrap();
+
+        for written in [
+            "bookmarks",
+            "bookmarks/",
+            "./bookmarks/.",
+            "link",
+            "link/../bookmarks",
+        ] {
+            assert_eq!(
+                canonical_export_path(&dir.file(written)),
+                folder,
+                "{}",
+                written
+            );
+        }
+    }
+
+    #[test]
+    fn export_paths_that_dont_exist_yet_are_resolved_as_written() {
+        let dir = TempDir::new("config");
+        std::os::unix::fs::symlink(&dir.0, dir.file("link")).unwrap();
+
+        assert_eq!(
+            canonical_export_path(&dir.file("link/new/./bookmarks/")),
+            dir.file("new/bookmarks")
+        );
+        assert_eq!(
+            canonical_export_path(&dir.file("link/new/../bookmarks")),
+            dir.file("bookmarks")
+        );
+    }
+}
@@ -0,0 +1,348 @@
+/// The watch daemon's control socket and the client used by `opbookmarks ctl`
+///
+/// Each connection carries one JSON request on a single line and gets one JSON response line
+/// back, such as `{"command":"sync","vault":"Private"}`. Syncs are run by the daemon's main loop
+/// and answered once they're done; everything else is answered straight away.
+use crate::resync::Drift;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Requests are a single short line, so anything longer is a confused client
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Export the changed vaults now, even while paused
+    Sync {
+        /// Exported account IDs. Every account when empty.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        accounts: Vec<String>,
+        /// A vault ID, name glob or `id:`, `name:` or `type:` selector
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        vault: Option<String>,
+        /// Re-export vaults even when their versions haven't changed
+        #[serde(default)]
+        full: bool,
+    },
+    Status,
+    /// Stop syncing on changes until resumed
+    Pause,
+    Resume,
+    Shutdown,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync: Option<SyncReport>,
+}
+
+impl Response {
+    fn error(error: String) -> Response {
+        Response {
+            ok: false,
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Status {
+    pub pid: u32,
+    pub export_path: PathBuf,
+    /// Unix time
+    pub started_at: u64,
+    /// `watching` or `polling`
+    pub mode: String,
+    pub paused: bool,
+    pub syncing: bool,
+    /// Syncs or checks that failed in a row
+    pub failures: u32,
+    /// What `op` said while nothing can be synced because it isn't signed in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_out: Option<String>,
+    /// Unix time `op` was first found signed out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_out_since: Option<u64>,
+    /// Accounts skipped as `op` isn't signed in to them
+    #[serde(default)]
+    pub signed_out_accounts: Vec<String>,
+    /// Unix time of the next scheduled full resync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_resync_at: Option<u64>,
+    /// What full resyncs and the export folder watcher repaired since the daemon started
+    #[serde(default)]
+    pub repaired: Drift,
+    pub last_sync: Option<SyncReport>,
+    /// Items exported per vault per account, from the manifest
+    pub items: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SyncReport {
+    /// What started the sync
+    pub reason: String,
+    /// Unix time
+    pub started_at: u64,
+    pub duration_ms: u64,
+    /// Vaults whose files were written
+    pub exported: usize,
+    /// Vaults that failed while the rest of the sync went ahead
+    pub errors: Vec<String>,
+    /// Why the sync as a whole failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What a full resync repaired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drift: Option<Drift>,
+}
+
+/// A sync asked for over the socket, waiting for the main loop to run it
+pub struct SyncRequest {
+    pub accounts: Vec<String>,
+    pub vault: Option<String>,
+    pub full: bool,
+    reply: Sender<SyncReport>,
+}
+
+impl SyncRequest {
+    pub fn reply(self, report: SyncReport) {
+        // The client may have given up waiting
+        let _ = self.reply.send(report);
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(PathBuf, std::io::Error),
+    InUse(PathBuf),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(path, e) => write!(f, "control socket {:?}: {}", path, e),
+            Error::InUse(path) => write!(
+                f,
+                "another daemon is already listening on the control socket {:?}",
+                path
+            ),
+        }
+    }
+}
+
+/// `control-<hash>.sock` in the cache folder, one per export path like the cache itself
+pub fn default_socket_path(export_path: &Path) -> PathBuf {
+    let mut path = crate::cache::cache_dir();
+    path.push(format!(
+        "control-{:016x}.sock",
+        crate::util::stable_hash(export_path.as_os_str().to_string_lossy().as_bytes())
+    ));
+    path
+}
+
+/// Listens on the socket for as long as it's kept. The socket file is removed when dropped.
+pub struct Server {
+    path: PathBuf,
+    status: Arc<Mutex<Status>>,
+    syncs: Receiver<SyncRequest>,
+}
+
+impl Server {
+    pub fn start(path: &Path, status: Status) -> Result<Server, Error> {
+        let io_error = |e| Error::IO(path.to_path_buf(), e);
+
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::InUse(path.to_path_buf()));
+            }
+            // Left behind by a daemon that didn't stop cleanly
+            std::fs::remove_file(path).map_err(io_error)?;
+        }
+        if let Some(folder) = path.parent() {
+            std::fs::create_dir_all(folder).map_err(io_error)?;
+        }
+
+        let listener = crate::util::bind_private_socket(path).map_err(io_error)?;
+
+        let status = Arc::new(Mutex::new(status));
+        let (tx, rx) = channel();
+        {
+            let status = status.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("Control socket error: {}", e);
+                            continue;
+                        }
+                    };
+                    let status = status.clone();
+                    let tx = tx.clone();
+                    std::thread::spawn(move || handle(stream, &status, &tx));
+                }
+            });
+        }
+
+        Ok(Server {
+            path: path.to_path_buf(),
+            status,
+            syncs: rx,
+        })
+    }
+
+    /// The next sync asked for by a client, if any
+    pub fn next_sync(&self) -> Option<SyncRequest> {
+        self.syncs.try_recv().ok()
+    }
+
+    /// Updates the status reported to clients
+    pub fn update<F: FnOnce(&mut Status)>(&self, f: F) {
+        f(&mut self.status.lock().unwrap());
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.status.lock().unwrap().paused
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn handle(mut stream: UnixStream, status: &Mutex<Status>, syncs: &Sender<SyncRequest>) {
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+
+    let mut line = String::new();
+    let read = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader.take(MAX_REQUEST_BYTES)).read_line(&mut line),
+        Err(e) => Err(e),
+    };
+
+    let response = match read {
+        Ok(_) => match serde_json::from_str::<Request>(&line) {
+            Ok(request) => respond(request, status, syncs),
+            Err(e) => Response::error(format!("invalid request: {}", e)),
+        },
+        Err(e) => Response::error(format!("unable to read request: {}", e)),
+    };
+
+    if let Ok(mut json) = serde_json::to_string(&response) {
+        json.push('\n');
+        let _ = stream.write_all(json.as_bytes());
+    }
+}
+
+fn respond(request: Request, status: &Mutex<Status>, syncs: &Sender<SyncRequest>) -> Response {
+    match request {
+        Request::Sync {
+            accounts,
+            vault,
+            full,
+        } => {
+            if let Some(vault) = &vault {
+                if let Err(e) = crate::filter::VaultSelector::parse(vault) {
+                    return Response::error(e);
+                }
+            }
+
+            let (reply, report) = channel();
+            let request = SyncRequest {
+                accounts,
+                vault,
+                full,
+                reply,
+            };
+            if syncs.send(request).is_err() {
+                return Response::error("the daemon is stopping".to_string());
+            }
+            crate::daemon::wake();
+
+            match report.recv() {
+                Ok(report) => Response {
+                    ok: report.error.is_none() && report.errors.is_empty(),
+                    sync: Some(report),
+                    ..Default::default()
+                },
+                Err(_) => Response::error("the daemon stopped before syncing".to_string()),
+            }
+        }
+        Request::Status => current_status(status),
+        Request::Pause => {
+            status.lock().unwrap().paused = true;
+            println!("Paused by a control request");
+            current_status(status)
+        }
+        Request::Resume => {
+            status.lock().unwrap().paused = false;
+            println!("Resumed by a control request");
+            crate::daemon::wake();
+            current_status(status)
+        }
+        Request::Shutdown => {
+            println!("Shutdown requested by a control request");
+            crate::daemon::request_shutdown();
+            Response {
+                ok: true,
+                ..Default::default()
+            }
+        }
+    }
+}
+
+fn current_status(status: &Mutex<Status>) -> Response {
+    let mut status = status.lock().unwrap().clone();
+
+    let manifest = crate::manifest::load(&status.export_path);
+    for (account_id, vaults) in manifest.accounts.iter() {
+        let counts = status.items.entry(account_id.clone()).or_default();
+        for (vault_id, vault) in vaults.iter() {
+            counts.insert(vault_id.clone(), vault.items);
+        }
+    }
+
+    Response {
+        ok: true,
+        status: Some(status),
+        ..Default::default()
+    }
+}
+
+/// Sends a request to the daemon listening on `path` and waits for its response
+pub fn send(path: &Path, request: &Request) -> Result<Response, String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        format!(
+            "unable to connect to {:?}: {}. Is `opbookmarks watch` running for this export path?",
+            path, e
+        )
+    })?;
+
+    let mut json = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    json.push('\n');
+    stream
+        .write_all(json.as_bytes())
+        .map_err(|e| format!("unable to send request: {}", e))?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("unable to read response: {}", e))?;
+    serde_json::from_str(&line).map_err(|e| format!("invalid response {:?}: {}", line, e))
+}
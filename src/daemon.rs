@@ -0,0 +1,402 @@
+/// Signal handling, the PID file and sync reporting for the `watch` daemon
+///
+/// Signals are received on a background thread and recorded in flags that the daemon checks
+/// between syncs, and that a sync checks between vaults, so files and the cache are never left
+/// half written by a graceful shutdown.
+use crate::config::Settings;
+use crate::control;
+use crate::events;
+use crate::sync::{self, SyncError, SyncOptions};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long the daemon can go without checking for signals while waiting for changes
+pub const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The daemon exits after this many syncs or checks in a row fail. Failing because `op` isn't
+/// signed in doesn't count.
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+/// How long to wait before the first retry while `op` isn't signed in. It doubles with every
+/// retry up to `MAX_SIGN_IN_RETRY`.
+const MIN_SIGN_IN_RETRY: Duration = Duration::from_secs(10);
+const MAX_SIGN_IN_RETRY: Duration = Duration::from_secs(120);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+static FULL_SYNC: AtomicBool = AtomicBool::new(false);
+/// Set when the control socket has a request for the main loop
+static WAKE: AtomicBool = AtomicBool::new(false);
+
+/// SIGTERM and SIGINT stop the daemon once the vault being exported is done, and a second one
+/// exits immediately. SIGHUP reloads the configuration and SIGUSR1 asks for a full sync.
+pub fn handle_signals(pid_file: Option<PathBuf>) -> std::io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP, SIGUSR1])?;
+
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+                SIGUSR1 => FULL_SYNC.store(true, Ordering::SeqCst),
+                _ => {
+                    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+                        // The cache is only saved at the end of a sync, so vaults being exported
+                        // now are exported again by the next run
+                        eprintln!("Exiting without finishing the current sync");
+                        if let Some(path) = &pid_file {
+                            let _ = std::fs::remove_file(path);
+                        }
+                        std::process::exit(128 + signal);
+                    }
+                    println!(
+                        "Stopping once the current sync is done. Signal again to stop immediately."
+                    );
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Stops the daemon as SIGTERM would
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Ends a `sleep` early so the main loop can handle a control request
+pub fn wake() {
+    WAKE.store(true, Ordering::SeqCst);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// True once for every SIGHUP received
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// True once for every SIGUSR1 received
+pub fn take_full_sync() -> bool {
+    FULL_SYNC.swap(false, Ordering::SeqCst)
+}
+
+fn signal_pending() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+        || RELOAD.load(Ordering::SeqCst)
+        || FULL_SYNC.load(Ordering::SeqCst)
+}
+
+/// Sleeps for `duration`, waking early when a signal or control request needs handling. Keeps
+/// sending systemd watchdog heartbeats, as polling can sleep for longer than the watchdog allows.
+pub fn sleep(duration: Duration) {
+    let until = Instant::now() + duration;
+
+    while !signal_pending() && !WAKE.swap(false, Ordering::SeqCst) {
+        crate::systemd::watchdog();
+        let now = Instant::now();
+        if now >= until {
+            return;
+        }
+        std::thread::sleep(std::cmp::min(until - now, SIGNAL_CHECK_INTERVAL));
+    }
+}
+
+/// Whether `op` is signed in, going by the last sync. While it isn't, changes don't trigger
+/// syncs and the daemon retries on a schedule instead, so a locked 1Password doesn't fail every
+/// sync. Each change of state is logged once.
+#[derive(Default)]
+pub struct SignIn {
+    /// What `op` said when nothing could be synced, and when (Unix time) that started
+    signed_out: Option<(String, u64)>,
+    /// Accounts skipped by syncs that exported the others
+    accounts: BTreeMap<String, String>,
+    retry_delay: Duration,
+    next_retry: Option<Instant>,
+}
+
+impl SignIn {
+    /// Records a sync that couldn't export anything as no account is signed in
+    pub fn signed_out(&mut self, message: &str) {
+        match &self.signed_out {
+            Some(_) => {
+                self.retry_delay = std::cmp::min(self.retry_delay * 2, MAX_SIGN_IN_RETRY);
+            }
+            None => {
+                eprintln!(
+                    "op isn't signed in or 1Password is locked: {}. Nothing is removed while signed out, and syncing resumes once signed in again.",
+                    message.trim_end()
+                );
+                crate::systemd::status("Waiting for op to be signed in");
+                self.signed_out = Some((message.to_string(), crate::manifest::now()));
+                self.retry_delay = MIN_SIGN_IN_RETRY;
+            }
+        }
+        self.next_retry = Some(Instant::now() + self.retry_delay);
+    }
+
+    /// Records a sync that could use `op`, skipping the accounts it isn't signed in to
+    pub fn signed_in(&mut self, skipped: &BTreeMap<String, String>) {
+        if let Some((_, since)) = self.signed_out.take() {
+            println!(
+                "op is signed in again after {}, syncing resumed",
+                crate::util::format_duration(crate::manifest::now().saturating_sub(since))
+            );
+        }
+        self.next_retry = None;
+
+        for (account_id, message) in skipped.iter() {
+            if !self.accounts.contains_key(account_id) {
+                eprintln!(
+                    "Skipping account {} until op is signed in to it: {}",
+                    account_id,
+                    message.trim_end()
+                );
+            }
+        }
+        for account_id in self.accounts.keys() {
+            if !skipped.contains_key(account_id) {
+                println!("op is signed in to account {} again", account_id);
+            }
+        }
+        self.accounts = skipped.clone();
+    }
+
+    pub fn is_signed_out(&self) -> bool {
+        self.signed_out.is_some()
+    }
+
+    /// True when it's time to try syncing again while signed out
+    pub fn retry_due(&self) -> bool {
+        matches!(self.next_retry, Some(at) if Instant::now() >= at)
+    }
+
+    /// How long until the next retry, if signed out
+    pub fn retry_in(&self) -> Option<Duration> {
+        self.next_retry
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Copies the state into the status reported to control clients
+    pub fn report(&self, status: &mut crate::control::Status) {
+        status.signed_out = self.signed_out.as_ref().map(|(message, _)| message.clone());
+        status.signed_out_since = self.signed_out.as_ref().map(|(_, since)| *since);
+        status.signed_out_accounts = self.accounts.keys().cloned().collect();
+    }
+}
+
+/// Runs the daemon's syncs, counting failures in a row, keeping track of whether `op` is signed
+/// in and reporting each result to systemd, control clients and event consumers
+pub struct Daemon {
+    pub failures: u32,
+    pub sign_in: SignIn,
+    control: Option<control::Server>,
+    events: Option<events::Stream>,
+}
+
+impl Daemon {
+    /// Starts the event stream, if any, and the control socket. Carries on without a control
+    /// socket unless another daemon is using it.
+    pub fn start(settings: &Settings) -> Result<Daemon, String> {
+        // Started first so nothing else printed ends up among the events on stdout
+        let events = if settings.events_stdout
+            || settings.events_fifo.is_some()
+            || settings.events_socket.is_some()
+        {
+            let stream = events::Stream::start(
+                settings.events_stdout,
+                settings.events_fifo.as_deref(),
+                settings.events_socket.as_deref(),
+            )
+            .map_err(|e| e.to_string())?;
+            Some(stream)
+        } else {
+            None
+        };
+
+        let status = control::Status {
+            pid: std::process::id(),
+            export_path: settings.export_path.clone(),
+            started_at: crate::manifest::now(),
+            ..Default::default()
+        };
+        let control = match control::Server::start(&settings.control_socket, status) {
+            Ok(control) => {
+                println!(
+                    "Listening for control requests on {:?}",
+                    settings.control_socket
+                );
+                Some(control)
+            }
+            Err(e @ control::Error::InUse(_)) => return Err(e.to_string()),
+            Err(e) => {
+                eprintln!("Continuing without a control socket: {}", e);
+                None
+            }
+        };
+
+        Ok(Daemon {
+            failures: 0,
+            sign_in: SignIn::default(),
+            control,
+            events,
+        })
+    }
+
+    /// Whether syncs should describe their changes as events
+    pub fn wants_events(&self) -> bool {
+        self.events.is_some()
+    }
+
+    /// Updates the status reported to control clients
+    pub fn update<F: FnOnce(&mut control::Status)>(&self, f: F) {
+        if let Some(control) = &self.control {
+            control.update(f);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        matches!(&self.control, Some(control) if control.is_paused())
+    }
+
+    pub fn next_request(&self) -> Option<control::SyncRequest> {
+        self.control.as_ref().and_then(|c| c.next_sync())
+    }
+
+    pub fn sync(
+        &mut self,
+        settings: &Settings,
+        options: &SyncOptions,
+        reason: &str,
+    ) -> control::SyncReport {
+        println!("{}. Updating metadata files...", reason);
+        self.update(|status| status.syncing = true);
+
+        let started = Instant::now();
+        let mut report = control::SyncReport {
+            reason: reason.to_string(),
+            started_at: crate::manifest::now(),
+            ..Default::default()
+        };
+
+        let result = sync::run(settings, options);
+        let was_signed_out = self.sign_in.is_signed_out();
+        match &result {
+            // Not a failure: the daemon waits for op to be signed in again rather than giving up
+            Ok(summary) if summary.all_signed_out() => {
+                report.exported = summary.exported;
+                report.errors = summary.errors.clone();
+                report.drift = summary.drift.clone();
+                if let Some(message) = summary.signed_out.values().next() {
+                    self.sign_in.signed_out(message);
+                }
+            }
+            Err(e @ SyncError::SignedOut(message)) => {
+                self.sign_in.signed_out(message);
+                report.error = Some(e.to_string());
+            }
+            Ok(summary) => {
+                self.failures = 0;
+                self.sign_in.signed_in(&summary.signed_out);
+                report.exported = summary.exported;
+                report.errors = summary.errors.clone();
+                report.drift = summary.drift.clone();
+                if report.errors.is_empty() {
+                    crate::systemd::status("Last sync succeeded");
+                } else {
+                    crate::systemd::status(&format!(
+                        "Last sync finished with {} errors",
+                        report.errors.len()
+                    ));
+                }
+            }
+            Err(e) => {
+                eprintln!("Unable to update metadata files: {}", e);
+                self.failures += 1;
+                crate::systemd::status(&format!(
+                    "Last sync failed ({} in a row): {}",
+                    self.failures, e
+                ));
+                report.error = Some(e.to_string());
+            }
+        }
+        report.duration_ms = started.elapsed().as_millis() as u64;
+
+        let (failures, sign_in) = (self.failures, &self.sign_in);
+        self.update(|status| {
+            status.syncing = false;
+            status.failures = failures;
+            status.last_sync = Some(report.clone());
+            if let Some(drift) = &report.drift {
+                status.repaired.add(drift);
+            }
+            sign_in.report(status);
+        });
+
+        if let (Some(events), Ok(summary)) = (&self.events, &result) {
+            events.emit(&summary.events);
+        }
+
+        // Hook failures aren't sync failures, so they don't count towards giving up. Hooks are
+        // told once that op isn't signed in rather than after every retry.
+        if !(was_signed_out && self.sign_in.is_signed_out()) {
+            sync::run_hooks(settings, options, &result);
+        }
+        report
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(PathBuf, std::io::Error),
+    Running(PathBuf, u32),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(path, e) => write!(f, "unable to write PID file {:?}: {}", path, e),
+            Error::Running(path, pid) => write!(
+                f,
+                "another daemon is running as PID {} according to {:?}",
+                pid, path
+            ),
+        }
+    }
+}
+
+/// Holds the daemon's PID for service managers and scripts. Removed when dropped.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Writes the PID, replacing a file left behind by a daemon that's no longer running
+    pub fn create(path: &Path) -> Result<PidFile, Error> {
+        let previous = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok());
+
+        match previous {
+            Some(pid) if pid != std::process::id() && crate::lock::is_running(pid) => {
+                return Err(Error::Running(path.to_path_buf(), pid));
+            }
+            Some(pid) => println!("Replacing stale PID file {:?} of PID {}", path, pid),
+            None => {}
+        }
+
+        crate::util::write_file(path.to_path_buf(), format!("{}\n", std::process::id()))
+            .map_err(|e| Error::IO(path.to_path_buf(), e))?;
+        Ok(PidFile(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            eprintln!("Unable to remove PID file {:?}: {}", self.0, e);
+        }
+    }
+}
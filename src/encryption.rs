@@ -0,0 +1,276 @@
+/// Writes each vault's metadata as a single age encrypted index for consumers that hold a key
+use crate::op7_metadata::OP7ItemMetaData;
+use age::secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const INDEX_EXTENSION: &str = "opbookmarks.age";
+const PASSPHRASE_ENV: &str = "OPBOOKMARKS_PASSPHRASE";
+
+/// How metadata is written to the export path. Several formats can be written at once.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    /// One plain JSON file per item, as 1Password 7 wrote them
+    Json,
+    /// One age encrypted JSON array per vault
+    Age,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "age" => Ok(ExportFormat::Age),
+            _ => Err(format!(
+                "unknown export format {:?}, expected json or age",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportFormat::Json => write!(f, "json"),
+            ExportFormat::Age => write!(f, "age"),
+        }
+    }
+}
+
+/// `[profiles.<name>.encryption]` from the config file. Either recipients or a passphrase can be
+/// used, as age doesn't allow both in one file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// age X25519 public keys, `age1...`
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// A file containing the passphrase. `OPBOOKMARKS_PASSPHRASE` is used when neither this nor
+    /// any recipients are set.
+    pub passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Encryption {
+    Recipients(Vec<age::x25519::Recipient>),
+    /// Read when needed so the passphrase isn't held for the life of a watch daemon
+    PassphraseFile(PathBuf),
+    PassphraseEnv,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(PathBuf, std::io::Error),
+    Config(String),
+    Encrypt(age::EncryptError),
+    Decrypt(PathBuf, age::DecryptError),
+    Json(PathBuf, serde_json::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(path, e) => write!(f, "{:?}: {}", path, e),
+            Error::Config(e) => write!(f, "{}", e),
+            Error::Encrypt(e) => write!(f, "unable to encrypt: {}", e),
+            Error::Decrypt(path, e) => write!(f, "unable to decrypt {:?}: {}", path, e),
+            Error::Json(path, e) => write!(f, "invalid index {:?}: {}", path, e),
+        }
+    }
+}
+
+impl Encryption {
+    pub fn from_config(config: &EncryptionConfig) -> Result<Encryption, Error> {
+        if !config.recipients.is_empty() && config.passphrase_file.is_some() {
+            return Err(Error::Config(
+                "encryption takes either recipients or a passphrase_file, not both".to_string(),
+            ));
+        }
+
+        if let Some(path) = &config.passphrase_file {
+            return Ok(Encryption::PassphraseFile(crate::config::expand_home(path)));
+        }
+
+        if config.recipients.is_empty() {
+            return Ok(Encryption::PassphraseEnv);
+        }
+
+        let recipients = config
+            .recipients
+            .iter()
+            .map(|r| {
+                r.parse::<age::x25519::Recipient>()
+                    .map_err(|e| Error::Config(format!("invalid age recipient {:?}: {}", r, e)))
+            })
+            .collect::<Result<Vec<age::x25519::Recipient>, Error>>()?;
+        Ok(Encryption::Recipients(recipients))
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let encryptor = match self {
+            Encryption::Recipients(recipients) => {
+                age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                    .map_err(Error::Encrypt)?
+            }
+            _ => age::Encryptor::with_user_passphrase(self.passphrase()?),
+        };
+
+        let mut ciphertext = vec![];
+        let mut writer = encryptor
+            .wrap_output(&mut ciphertext)
+            .expect("writing to memory can't fail");
+        writer
+            .write_all(plaintext)
+            .and_then(|_| writer.finish())
+            .expect("writing to memory can't fail");
+        Ok(ciphertext)
+    }
+
+    /// The passphrase for this configuration. Recipients have none.
+    pub fn passphrase(&self) -> Result<SecretString, Error> {
+        match self {
+            Encryption::Recipients(_) => Err(Error::Config(
+                "encryption is configured with recipients, not a passphrase".to_string(),
+            )),
+            Encryption::PassphraseFile(path) => {
+                let passphrase =
+                    std::fs::read_to_string(path).map_err(|e| Error::IO(path.clone(), e))?;
+                Ok(SecretString::from(
+                    passphrase.trim_end_matches(['\r', '\n']),
+                ))
+            }
+            Encryption::PassphraseEnv => match std::env::var(PASSPHRASE_ENV) {
+                Ok(passphrase) if !passphrase.is_empty() => Ok(SecretString::from(passphrase)),
+                _ => Err(Error::Config(format!(
+                    "no age recipients or passphrase_file are configured and {} isn't set",
+                    PASSPHRASE_ENV
+                ))),
+            },
+        }
+    }
+}
+
+pub fn index_path(export_path: &Path, account_id: &str, vault_id: &str) -> PathBuf {
+    let mut path = export_path.to_path_buf();
+    path.push(account_id);
+    path.push(format!("{}.{}", vault_id, INDEX_EXTENSION));
+    path
+}
+
+pub fn is_index(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.ends_with(&format!(".{}", INDEX_EXTENSION)))
+        .unwrap_or(false)
+}
+
+/// Replaces the vault's index with the given items. Returns the encrypted index.
+pub fn write_index(
+    export_path: &Path,
+    account_id: &str,
+    vault_id: &str,
+    items: &[OP7ItemMetaData],
+    encryption: &Encryption,
+) -> Result<Vec<u8>, Error> {
+    let path = index_path(export_path, account_id, vault_id);
+    let json = serde_json::to_vec(items).map_err(|e| Error::Json(path.clone(), e))?;
+    let ciphertext = encryption.encrypt(&json)?;
+
+    // Written next to the index and renamed over it so readers never see a partial file
+    let mut partial = path.clone();
+    partial.set_extension("age.partial");
+    crate::util::write_private_file(&partial, &ciphertext)
+        .and_then(|_| std::fs::rename(&partial, &path))
+        .map_err(|e| Error::IO(path, e))?;
+    Ok(ciphertext)
+}
+
+/// Returns whether there was an index to remove
+pub fn remove_index(export_path: &Path, account_id: &str, vault_id: &str) -> std::io::Result<bool> {
+    match std::fs::remove_file(index_path(export_path, account_id, vault_id)) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The IDs of the vaults with an index in an account's folder
+pub fn indexed_vault_ids(export_path: &Path, account_id: &str) -> std::io::Result<Vec<String>> {
+    let mut path = export_path.to_path_buf();
+    path.push(account_id);
+
+    let files = match std::fs::read_dir(&path) {
+        Ok(files) => files,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let suffix = format!(".{}", INDEX_EXTENSION);
+    let mut vault_ids = vec![];
+    for file in files {
+        let name = file?.file_name();
+        if let Some(vault_id) = name.to_str().and_then(|n| n.strip_suffix(&suffix)) {
+            vault_ids.push(vault_id.to_string());
+        }
+    }
+
+    vault_ids.sort();
+    Ok(vault_ids)
+}
+
+/// Every index file in the export path
+pub fn find_indexes(export_path: &Path) -> Vec<PathBuf> {
+    let mut indexes = vec![];
+
+    let accounts = match std::fs::read_dir(export_path) {
+        Ok(accounts) => accounts,
+        Err(_) => return indexes,
+    };
+
+    for account in accounts.flatten() {
+        if let Ok(files) = std::fs::read_dir(account.path()) {
+            indexes.extend(files.flatten().map(|f| f.path()).filter(|p| is_index(p)));
+        }
+    }
+
+    indexes.sort();
+    indexes
+}
+
+/// Decrypts an index with an age identity file or, without one, the configured passphrase
+pub fn read_index(
+    path: &Path,
+    identity_file: Option<&Path>,
+    encryption: Option<&Encryption>,
+) -> Result<Vec<OP7ItemMetaData>, Error> {
+    let ciphertext = std::fs::read(path).map_err(|e| Error::IO(path.to_path_buf(), e))?;
+    let decryptor = age::Decryptor::new_buffered(&ciphertext[..])
+        .map_err(|e| Error::Decrypt(path.to_path_buf(), e))?;
+
+    let identities: Vec<Box<dyn age::Identity>> = match identity_file {
+        Some(identity_file) => age::IdentityFile::from_file(identity_file.display().to_string())
+            .map_err(|e| Error::IO(identity_file.to_path_buf(), e))?
+            .into_identities()
+            .map_err(|e| Error::Decrypt(identity_file.to_path_buf(), e))?,
+        None => {
+            let passphrase = encryption
+                .unwrap_or(&Encryption::PassphraseEnv)
+                .passphrase()?;
+            vec![Box::new(age::scrypt::Identity::new(passphrase))]
+        }
+    };
+
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .map_err(|e| Error::Decrypt(path.to_path_buf(), e))?;
+    let mut json = vec![];
+    std::io::Read::read_to_end(&mut reader, &mut json)
+        .map_err(|e| Error::IO(path.to_path_buf(), e))?;
+
+    serde_json::from_slice(&json).map_err(|e| Error::Json(path.to_path_buf(), e))
+}
@@ -0,0 +1,669 @@
+/// Streams what each sync changed as newline-delimited JSON, one line per event
+///
+/// Events are found by comparing the item versions in the cache with the items just fetched from
+/// `op`. Item metadata is the same, already redacted, metadata as the exported files: `before` is
+/// read from the files a sync is about to replace and `after` is what it wrote.
+use crate::cache::BookmarkCache;
+use crate::hooks::VaultChanges;
+use crate::op::{AccountDetails, VaultDetails};
+use crate::op7_metadata::{self, OP7ItemMetaData};
+use crate::sync::{ExportedVault, Observer, SyncSummary};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a socket client can keep a sync waiting before it's dropped
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+type ItemVersions = HashMap<String, HashMap<String, BTreeMap<String, usize>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    Added,
+    Updated,
+    Removed,
+    /// Removed from one vault and added to another of the same account by the same sync
+    Moved,
+    VaultAdded,
+    /// The vault's attributes, such as its name, changed
+    VaultUpdated,
+    VaultRemoved,
+    AccountAdded,
+    AccountRemoved,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    /// Unix time of the sync that found the change
+    pub at: u64,
+    pub account_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_id: Option<String>,
+    /// Where a moved item was before
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_vault_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub item_id: Option<String>,
+    /// Item metadata or vault details. `null` when there was nothing before, or the previous
+    /// metadata wasn't exported as JSON.
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Exported item metadata by account, vault and item ID, from before and after a sync
+#[derive(Default)]
+pub struct ItemMetadata {
+    pub before: HashMap<(String, String, String), OP7ItemMetaData>,
+    pub after: HashMap<(String, String, String), OP7ItemMetaData>,
+}
+
+impl ItemMetadata {
+    pub fn record_before(&mut self, items: Vec<OP7ItemMetaData>) {
+        for item in items {
+            self.before.insert(key(&item), item);
+        }
+    }
+
+    /// Records the files of every exported vault of an account that's about to be purged
+    pub fn record_account_before(
+        &mut self,
+        export_path: &Path,
+        item_versions: &ItemVersions,
+        account_id: &str,
+    ) {
+        if let Some(vaults) = item_versions.get(account_id) {
+            for vault_id in vaults.keys() {
+                self.record_before(op7_metadata::read_vault_items(
+                    export_path,
+                    account_id,
+                    vault_id,
+                ));
+            }
+        }
+    }
+
+    pub fn record_after(&mut self, items: &[OP7ItemMetaData]) {
+        for item in items.iter() {
+            self.after.insert(key(item), item.clone());
+        }
+    }
+}
+
+/// Reads the files a sync is about to replace or remove, and keeps what it wrote
+impl Observer for ItemMetadata {
+    fn removing_account(&mut self, export_path: &Path, cache: &BookmarkCache, account_id: &str) {
+        self.record_account_before(export_path, &cache.item_versions, account_id);
+    }
+
+    fn removing_vault(&mut self, export_path: &Path, account_id: &str, vault_id: &str) {
+        self.record_before(op7_metadata::read_vault_items(
+            export_path,
+            account_id,
+            vault_id,
+        ));
+    }
+
+    fn exporting(
+        &mut self,
+        export_path: &Path,
+        account_id: &str,
+        vault_id: &str,
+        _same_settings: bool,
+    ) {
+        self.record_before(op7_metadata::read_vault_items(
+            export_path,
+            account_id,
+            vault_id,
+        ));
+    }
+
+    fn exported(
+        &mut self,
+        _account: &AccountDetails,
+        _vault: &VaultDetails,
+        exported: &ExportedVault,
+    ) -> Result<(), String> {
+        self.record_after(&exported.items);
+        Ok(())
+    }
+
+    fn finished(
+        &mut self,
+        summary: &mut SyncSummary,
+        previous: &BookmarkCache,
+        cache: &BookmarkCache,
+    ) {
+        summary.events = collect(
+            &summary.changes,
+            &previous.item_versions,
+            &cache.item_versions,
+            &previous.vaults_by_account_id,
+            &cache.vaults_by_account_id,
+            self,
+        );
+    }
+}
+
+fn key(item: &OP7ItemMetaData) -> (String, String, String) {
+    (
+        item.profile_uuid.clone(),
+        item.vault_uuid.clone(),
+        item.uuid.clone(),
+    )
+}
+
+/// Describes a sync's changes, ordered so accounts and vaults are added before their items and
+/// removed after them
+pub fn collect(
+    changes: &[VaultChanges],
+    previous_items: &ItemVersions,
+    current_items: &ItemVersions,
+    previous_vaults: &HashMap<String, Vec<VaultDetails>>,
+    current_vaults: &HashMap<String, Vec<VaultDetails>>,
+    metadata: &ItemMetadata,
+) -> Vec<Event> {
+    let at = crate::manifest::now();
+    let event = |kind: EventKind, account_id: &str| Event {
+        event: kind,
+        at,
+        account_id: account_id.to_string(),
+        vault_id: None,
+        from_vault_id: None,
+        item_id: None,
+        before: None,
+        after: None,
+    };
+    let vault_details =
+        |vaults: &HashMap<String, Vec<VaultDetails>>, account_id: &str, vault_id: &str| {
+            vaults
+                .get(account_id)
+                .and_then(|v| v.iter().find(|v| v.id == vault_id))
+                .and_then(|v| serde_json::to_value(v).ok())
+        };
+    let item = |items: &HashMap<(String, String, String), OP7ItemMetaData>,
+                account_id: &str,
+                vault_id: &str,
+                item_id: &str| {
+        items
+            .get(&(
+                account_id.to_string(),
+                vault_id.to_string(),
+                item_id.to_string(),
+            ))
+            .and_then(|i| serde_json::to_value(i).ok())
+    };
+
+    let sorted = |items: &ItemVersions| {
+        let mut vaults: Vec<(String, String)> = items
+            .iter()
+            .flat_map(|(account_id, vaults)| {
+                vaults
+                    .keys()
+                    .map(move |vault_id| (account_id.clone(), vault_id.clone()))
+            })
+            .collect();
+        vaults.sort();
+        vaults
+    };
+    let mut previous_accounts: Vec<&String> = previous_items.keys().collect();
+    previous_accounts.sort();
+    let mut current_accounts: Vec<&String> = current_items.keys().collect();
+    current_accounts.sort();
+
+    let mut events = vec![];
+    for account_id in current_accounts {
+        if !previous_items.contains_key(account_id) {
+            events.push(event(EventKind::AccountAdded, account_id));
+        }
+    }
+
+    for (account_id, vault_id) in sorted(current_items).iter() {
+        let existed = matches!(previous_items.get(account_id), Some(v) if v.contains_key(vault_id));
+        let before = vault_details(previous_vaults, account_id, vault_id);
+        let after = vault_details(current_vaults, account_id, vault_id);
+        let kind = if !existed {
+            EventKind::VaultAdded
+        } else if matches!((&before, &after), (Some(b), Some(a)) if b["attribute_version"] != a["attribute_version"])
+        {
+            EventKind::VaultUpdated
+        } else {
+            continue;
+        };
+        events.push(Event {
+            vault_id: Some(vault_id.clone()),
+            before: if kind == EventKind::VaultAdded {
+                None
+            } else {
+                before
+            },
+            after,
+            ..event(kind, account_id)
+        });
+    }
+
+    // An item removed from one vault and added to another is reported once, as moved
+    let mut removed_from: HashMap<(&str, &str), &str> = HashMap::new();
+    for vault in changes.iter() {
+        for item_id in vault.removed.iter() {
+            removed_from.insert((&vault.account_id, item_id), &vault.vault_id);
+        }
+    }
+    let mut moved: Vec<(&str, &str)> = vec![];
+
+    for vault in changes.iter() {
+        let (account_id, vault_id) = (vault.account_id.as_str(), vault.vault_id.as_str());
+        for item_id in vault.added.iter() {
+            let after = item(&metadata.after, account_id, vault_id, item_id);
+            match removed_from.get(&(account_id, item_id.as_str())) {
+                Some(from) if *from != vault_id => {
+                    moved.push((account_id, item_id));
+                    events.push(Event {
+                        vault_id: Some(vault_id.to_string()),
+                        from_vault_id: Some(from.to_string()),
+                        item_id: Some(item_id.clone()),
+                        before: item(&metadata.before, account_id, from, item_id),
+                        after,
+                        ..event(EventKind::Moved, account_id)
+                    });
+                }
+                _ => events.push(Event {
+                    vault_id: Some(vault_id.to_string()),
+                    item_id: Some(item_id.clone()),
+                    after,
+                    ..event(EventKind::Added, account_id)
+                }),
+            }
+        }
+        for item_id in vault.changed.iter() {
+            events.push(Event {
+                vault_id: Some(vault_id.to_string()),
+                item_id: Some(item_id.clone()),
+                before: item(&metadata.before, account_id, vault_id, item_id),
+                after: item(&metadata.after, account_id, vault_id, item_id),
+                ..event(EventKind::Updated, account_id)
+            });
+        }
+    }
+    for vault in changes.iter() {
+        let (account_id, vault_id) = (vault.account_id.as_str(), vault.vault_id.as_str());
+        for item_id in vault.removed.iter() {
+            if moved.contains(&(account_id, item_id.as_str())) {
+                continue;
+            }
+            events.push(Event {
+                vault_id: Some(vault_id.to_string()),
+                item_id: Some(item_id.clone()),
+                before: item(&metadata.before, account_id, vault_id, item_id),
+                ..event(EventKind::Removed, account_id)
+            });
+        }
+    }
+
+    for (account_id, vault_id) in sorted(previous_items).iter() {
+        if !matches!(current_items.get(account_id), Some(v) if v.contains_key(vault_id)) {
+            events.push(Event {
+                vault_id: Some(vault_id.clone()),
+                before: vault_details(previous_vaults, account_id, vault_id),
+                ..event(EventKind::VaultRemoved, account_id)
+            });
+        }
+    }
+    for account_id in previous_accounts {
+        if !current_items.contains_key(account_id) {
+            events.push(event(EventKind::AccountRemoved, account_id));
+        }
+    }
+    events
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(PathBuf, std::io::Error),
+    InUse(PathBuf),
+    NotFifo(PathBuf),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(path, e) => write!(f, "event stream {:?}: {}", path, e),
+            Error::InUse(path) => write!(
+                f,
+                "another daemon is already streaming events on {:?}",
+                path
+            ),
+            Error::NotFifo(path) => write!(f, "{:?} exists and isn't a FIFO", path),
+        }
+    }
+}
+
+/// Where events are written. Lines that can't be written, such as while nothing reads the FIFO,
+/// are dropped rather than holding up the daemon.
+pub struct Stream {
+    stdout: Option<std::fs::File>,
+    /// Kept open between syncs once a reader shows up, so a reader sees every sync's events
+    fifo: Option<(PathBuf, Mutex<Option<std::fs::File>>)>,
+    socket: Option<(PathBuf, Arc<Mutex<Vec<UnixStream>>>)>,
+}
+
+impl Stream {
+    pub fn start(
+        stdout: bool,
+        fifo: Option<&Path>,
+        socket: Option<&Path>,
+    ) -> Result<Stream, Error> {
+        let stdout = if stdout {
+            // Keep stdout for events alone by sending everything else printed to stderr
+            let file = unsafe {
+                let fd = libc::dup(1);
+                if fd < 0 || libc::dup2(2, 1) < 0 {
+                    return Err(Error::IO(
+                        PathBuf::from("stdout"),
+                        std::io::Error::last_os_error(),
+                    ));
+                }
+                std::fs::File::from_raw_fd(fd)
+            };
+            Some(file)
+        } else {
+            None
+        };
+
+        if let Some(fifo) = fifo {
+            create_fifo(fifo)?;
+        }
+
+        let socket = match socket {
+            Some(path) => Some((path.to_path_buf(), listen(path)?)),
+            None => None,
+        };
+
+        Ok(Stream {
+            stdout,
+            fifo: fifo.map(|f| (f.to_path_buf(), Mutex::new(None))),
+            socket,
+        })
+    }
+
+    pub fn emit(&self, events: &[Event]) {
+        if events.is_empty() {
+            return;
+        }
+
+        let mut lines = String::new();
+        for event in events.iter() {
+            match serde_json::to_string(event) {
+                Ok(json) => {
+                    lines.push_str(&json);
+                    lines.push('\n');
+                }
+                Err(e) => eprintln!("Unable to serialize event: {}", e),
+            }
+        }
+
+        if let Some(mut stdout) = self.stdout.as_ref() {
+            if let Err(e) = stdout
+                .write_all(lines.as_bytes())
+                .and_then(|_| stdout.flush())
+            {
+                eprintln!("Unable to write events to stdout: {}", e);
+            }
+        }
+        if let Some((path, fifo)) = &self.fifo {
+            write_fifo(path, &mut fifo.lock().unwrap(), &lines);
+        }
+        if let Some((_, clients)) = &self.socket {
+            // Clients that have gone away, or can't keep up, are disconnected
+            clients
+                .lock()
+                .unwrap()
+                .retain(|mut client| client.write_all(lines.as_bytes()).is_ok());
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn create_fifo(path: &Path) -> Result<(), Error> {
+    let io_error = |e| Error::IO(path.to_path_buf(), e);
+
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => return Ok(()),
+        Ok(_) => return Err(Error::NotFifo(path.to_path_buf())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(io_error(e)),
+    }
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder).map_err(io_error)?;
+    }
+
+    let c_path = std::ffi::CString::new(path.as_os_str().to_string_lossy().as_bytes())
+        .map_err(|e| io_error(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io_error(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Opening a FIFO without blocking fails when nothing is reading it, and a full FIFO fails the
+/// write, so a reader that goes away never stalls the daemon
+fn write_fifo(path: &Path, open: &mut Option<std::fs::File>, lines: &str) {
+    if open.is_none() {
+        let fifo = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path);
+        *open = match fifo {
+            Ok(fifo) => Some(fifo),
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => return,
+            Err(e) => {
+                eprintln!("Unable to open event FIFO {:?}: {}", path, e);
+                return;
+            }
+        };
+    }
+
+    if let Some(fifo) = open.as_mut() {
+        // Closed on errors so a reader that can't keep up sees the end of the stream rather than
+        // half a line followed by the next sync's events
+        match fifo.write_all(lines.as_bytes()) {
+            Ok(()) => {}
+            // The reader went away. The next sync waits for another.
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => *open = None,
+            Err(e) => {
+                eprintln!("Dropped events for FIFO {:?}: {}", path, e);
+                *open = None;
+            }
+        }
+    }
+}
+
+/// Accepts clients on a background thread. Each gets every event emitted while it's connected.
+fn listen(path: &Path) -> Result<Arc<Mutex<Vec<UnixStream>>>, Error> {
+    let io_error = |e| Error::IO(path.to_path_buf(), e);
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::InUse(path.to_path_buf()));
+        }
+        // Left behind by a daemon that didn't stop cleanly
+        std::fs::remove_file(path).map_err(io_error)?;
+    }
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder).map_err(io_error)?;
+    }
+
+    let listener = crate::util::bind_private_socket(path).map_err(io_error)?;
+
+    let clients = Arc::new(Mutex::new(vec![]));
+    {
+        let clients = clients.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let _ = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT));
+                        clients.lock().unwrap().push(stream);
+                    }
+                    Err(e) => eprintln!("Event socket error: {}", e),
+                }
+            }
+        });
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::item;
+    use crate::test_support::item_versions as versions;
+
+    fn vault(id: &str, attribute_version: usize) -> VaultDetails {
+        VaultDetails {
+            id: id.to_string(),
+            name: id.to_string(),
+            attribute_version,
+            content_version: 1,
+            vault_type: "PERSONAL".to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    fn vaults(entries: &[(&str, VaultDetails)]) -> HashMap<String, Vec<VaultDetails>> {
+        let mut all: HashMap<String, Vec<VaultDetails>> = HashMap::new();
+        for (account_id, vault) in entries {
+            all.entry(account_id.to_string())
+                .or_default()
+                .push(vault.clone());
+        }
+        all
+    }
+
+    fn describe(events: &[Event]) -> Vec<(EventKind, String)> {
+        events
+            .iter()
+            .map(|e| {
+                let id = e.item_id.as_ref().or(e.vault_id.as_ref());
+                (e.event, id.unwrap_or(&e.account_id).clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn item_moved_between_vaults_is_one_event() {
+        let previous = versions(&[("A", "v1", &[("i1", 1)]), ("A", "v2", &[])]);
+        let current = versions(&[("A", "v1", &[]), ("A", "v2", &[("i1", 1)])]);
+        let details = vaults(&[("A", vault("v1", 1)), ("A", vault("v2", 1))]);
+        let changes = crate::hooks::changes(&previous, &current, &HashMap::new());
+        let mut metadata = ItemMetadata::default();
+        metadata.record_before(vec![item("A", "v1", "i1", "before")]);
+        metadata.record_after(&[item("A", "v2", "i1", "after")]);
+
+        let events = collect(&changes, &previous, &current, &details, &details, &metadata);
+        assert_eq!(
+            describe(&events),
+            vec![(EventKind::Moved, "i1".to_string())]
+        );
+        let moved = &events[0];
+        assert_eq!(moved.vault_id.as_deref(), Some("v2"));
+        assert_eq!(moved.from_vault_id.as_deref(), Some("v1"));
+        assert_eq!(moved.before.as_ref().unwrap()["itemTitle"], "before");
+        assert_eq!(moved.after.as_ref().unwrap()["itemTitle"], "after");
+    }
+
+    #[test]
+    fn moves_between_accounts_are_removed_and_added() {
+        let previous = versions(&[("A", "v1", &[("i1", 1)]), ("B", "v1", &[])]);
+        let current = versions(&[("A", "v1", &[]), ("B", "v1", &[("i1", 1)])]);
+        let details = vaults(&[("A", vault("v1", 1)), ("B", vault("v1", 1))]);
+        let changes = crate::hooks::changes(&previous, &current, &HashMap::new());
+
+        let events = collect(
+            &changes,
+            &previous,
+            &current,
+            &details,
+            &details,
+            &ItemMetadata::default(),
+        );
+        assert_eq!(
+            describe(&events),
+            vec![
+                (EventKind::Added, "i1".to_string()),
+                (EventKind::Removed, "i1".to_string()),
+            ]
+        );
+        assert_eq!(events[0].account_id, "B");
+        assert_eq!(events[1].account_id, "A");
+    }
+
+    #[test]
+    fn containers_are_added_before_their_items_and_removed_after_them() {
+        let previous = versions(&[("A", "v1", &[("i1", 1)])]);
+        let current = versions(&[("B", "v2", &[("i2", 1)])]);
+        let previous_vaults = vaults(&[("A", vault("v1", 1))]);
+        let current_vaults = vaults(&[("B", vault("v2", 1))]);
+        let changes = crate::hooks::changes(&previous, &current, &HashMap::new());
+
+        let events = collect(
+            &changes,
+            &previous,
+            &current,
+            &previous_vaults,
+            &current_vaults,
+            &ItemMetadata::default(),
+        );
+        assert_eq!(
+            describe(&events),
+            vec![
+                (EventKind::AccountAdded, "B".to_string()),
+                (EventKind::VaultAdded, "v2".to_string()),
+                (EventKind::Added, "i2".to_string()),
+                (EventKind::Removed, "i1".to_string()),
+                (EventKind::VaultRemoved, "v1".to_string()),
+                (EventKind::AccountRemoved, "A".to_string()),
+            ]
+        );
+        assert!(events[1].before.is_none());
+        assert_eq!(events[4].before.as_ref().unwrap()["id"], "v1");
+    }
+
+    #[test]
+    fn vault_attribute_changes_are_updates() {
+        let items = versions(&[("A", "v1", &[("i1", 1)]), ("A", "v2", &[("i2", 1)])]);
+        let previous_vaults = vaults(&[("A", vault("v1", 1)), ("A", vault("v2", 1))]);
+        let current_vaults = vaults(&[("A", vault("v1", 2)), ("A", vault("v2", 1))]);
+
+        let events = collect(
+            &[],
+            &items,
+            &items,
+            &previous_vaults,
+            &current_vaults,
+            &ItemMetadata::default(),
+        );
+        assert_eq!(
+            describe(&events),
+            vec![(EventKind::VaultUpdated, "v1".to_string())]
+        );
+        assert_eq!(events[0].before.as_ref().unwrap()["attribute_version"], 1);
+        assert_eq!(events[0].after.as_ref().unwrap()["attribute_version"], 2);
+    }
+}
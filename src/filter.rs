@@ -0,0 +1,365 @@
+/// Decides which accounts, vaults and items are exported
+use crate::op::{AccountDetails, AccountOverview, Error, ItemOverview, VaultDetails};
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+pub struct AccountCandidate {
+    pub overview: AccountOverview,
+    /// Only loaded when a selector can't be resolved from the overview, as `op account get` may
+    /// require authorizing an account the user didn't ask for.
+    pub details: Option<AccountDetails>,
+}
+
+/// Returns the indexes of the candidates picked by the selectors, in selector order. A selector
+/// matches a user UUID, account ID, email, sign-in address (with or without `.1password.com`) or
+/// account name. Selectors containing `*`, `?` or `[` are globs and may match several accounts;
+/// any other selector matching more than one account is an error. Accounts whose details can't be
+/// loaded don't match selectors that need them, unless `strict`, which fails instead.
+pub fn select_accounts<F>(
+    selectors: &[String],
+    candidates: &mut [AccountCandidate],
+    load_details: F,
+    strict: bool,
+) -> Result<Vec<usize>, Error>
+where
+    F: Fn(&AccountOverview) -> Result<AccountDetails, Error>,
+{
+    let mut selected: Vec<usize> = vec![];
+    // Accounts whose details couldn't be loaded, such as suspended or signed out ones
+    let mut unavailable: Vec<usize> = vec![];
+
+    for selector in selectors.iter() {
+        let pattern = if is_glob(selector) {
+            Some(Pattern::new(selector).map_err(|e| {
+                Error::AccountSelection(format!("invalid account pattern {:?}: {}", selector, e))
+            })?)
+        } else {
+            None
+        };
+        let is_match = |value: &str| match &pattern {
+            Some(pattern) => pattern.matches_with(value, MATCH_OPTIONS),
+            None => value.eq_ignore_ascii_case(selector),
+        };
+
+        let mut matches: Vec<usize> = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| {
+                is_match(&c.overview.user_uuid)
+                    || is_match(&c.overview.email)
+                    || is_match(&c.overview.url)
+                    || is_match(sign_in_shorthand(&c.overview.url))
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        // Names and account IDs need `op account get`
+        if matches.is_empty() || pattern.is_some() {
+            for (i, candidate) in candidates.iter_mut().enumerate() {
+                if candidate.details.is_none() && !unavailable.contains(&i) {
+                    match load_details(&candidate.overview) {
+                        Ok(details) => candidate.details = Some(details),
+                        Err(e) if strict => return Err(e),
+                        Err(e) => {
+                            eprintln!(
+                                "Unable to match account {} against selectors: {}",
+                                candidate.overview.user_uuid, e
+                            );
+                            unavailable.push(i);
+                        }
+                    }
+                }
+
+                let details = match &candidate.details {
+                    Some(details) => details,
+                    None => continue,
+                };
+                if !matches.contains(&i) && (is_match(&details.id) || is_match(&details.name)) {
+                    matches.push(i);
+                }
+            }
+        }
+
+        if matches.is_empty() {
+            let message = format!("no account matches {:?}", selector);
+            if strict {
+                return Err(Error::AccountSelection(message));
+            }
+            eprintln!("Cannot include specified account for export: {}", message);
+            continue;
+        }
+
+        if pattern.is_none() && matches.len() > 1 {
+            let candidates: Vec<String> =
+                matches.iter().map(|i| describe(&candidates[*i])).collect();
+            return Err(Error::AccountSelection(format!(
+                "{:?} matches more than one account: {}",
+                selector,
+                candidates.join(", ")
+            )));
+        }
+
+        for i in matches {
+            if !selected.contains(&i) {
+                selected.push(i);
+            }
+        }
+    }
+
+    Ok(selected)
+}
+
+fn is_glob(selector: &str) -> bool {
+    selector.contains(['*', '?', '['])
+}
+
+/// `my.1password.com` can be selected as `my`
+fn sign_in_shorthand(url: &str) -> &str {
+    let url = url.trim_start_matches("https://");
+    url.strip_suffix(".1password.com").unwrap_or(url)
+}
+
+fn describe(candidate: &AccountCandidate) -> String {
+    match &candidate.details {
+        Some(details) => format!(
+            "{} ({} on {}, {})",
+            details.name,
+            candidate.overview.email,
+            candidate.overview.url,
+            candidate.overview.user_uuid
+        ),
+        None => format!(
+            "{} on {} ({})",
+            candidate.overview.email, candidate.overview.url, candidate.overview.user_uuid
+        ),
+    }
+}
+
+/// A `[[profiles.<name>.vault_filters]]` entry from the config file
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultFilterConfig {
+    /// Limits the filter to the account with this ID or name. Applies to every account when absent.
+    pub account: Option<String>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum VaultSelector {
+    Id(String),
+    Name(Pattern),
+    Type(String),
+    /// No prefix: matches the ID or the name
+    IdOrName(Pattern),
+}
+
+impl VaultSelector {
+    /// Parses `id:<id>`, `name:<glob>`, `type:<type>` or a bare ID or name glob.
+    pub fn parse(selector: &str) -> Result<VaultSelector, String> {
+        let pattern = |p: &str| {
+            Pattern::new(p).map_err(|e| format!("invalid vault pattern {:?}: {}", selector, e))
+        };
+
+        match selector.split_once(':') {
+            Some(("id", id)) => Ok(VaultSelector::Id(id.to_string())),
+            Some(("name", name)) => Ok(VaultSelector::Name(pattern(name)?)),
+            Some(("type", vault_type)) => Ok(VaultSelector::Type(vault_type.to_uppercase())),
+            Some((prefix, _)) => Err(format!(
+                "unknown vault selector prefix {:?} in {:?}, expected id:, name: or type:",
+                prefix, selector
+            )),
+            None => Ok(VaultSelector::IdOrName(pattern(selector)?)),
+        }
+    }
+
+    pub fn matches(&self, vault: &VaultDetails) -> bool {
+        match self {
+            VaultSelector::Id(id) => vault.id == *id,
+            VaultSelector::Name(pattern) => pattern.matches_with(&vault.name, MATCH_OPTIONS),
+            VaultSelector::Type(vault_type) => vault.vault_type.eq_ignore_ascii_case(vault_type),
+            VaultSelector::IdOrName(pattern) => {
+                pattern.as_str() == vault.id || pattern.matches_with(&vault.name, MATCH_OPTIONS)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VaultFilter {
+    pub account: Option<String>,
+    pub include: Vec<VaultSelector>,
+    pub exclude: Vec<VaultSelector>,
+}
+
+impl VaultFilter {
+    pub fn parse(config: &VaultFilterConfig) -> Result<VaultFilter, String> {
+        let parse_all = |selectors: &[String]| -> Result<Vec<VaultSelector>, String> {
+            selectors.iter().map(|s| VaultSelector::parse(s)).collect()
+        };
+
+        Ok(VaultFilter {
+            account: config.account.clone(),
+            include: parse_all(&config.include)?,
+            exclude: parse_all(&config.exclude)?,
+        })
+    }
+
+    fn applies_to(&self, account: &AccountDetails) -> bool {
+        match &self.account {
+            Some(selector) => {
+                account.id.eq_ignore_ascii_case(selector)
+                    || account.name.eq_ignore_ascii_case(selector)
+            }
+            None => true,
+        }
+    }
+}
+
+/// A vault is exported when it matches an include of every filter for its account that has any,
+/// and none of their excludes.
+pub fn is_vault_exported(
+    filters: &[VaultFilter],
+    account: &AccountDetails,
+    vault: &VaultDetails,
+) -> bool {
+    filters.iter().filter(|f| f.applies_to(account)).all(|f| {
+        (f.include.is_empty() || f.include.iter().any(|s| s.matches(vault)))
+            && !f.exclude.iter().any(|s| s.matches(vault))
+    })
+}
+
+/// Items carrying this tag are never exported, letting anyone opt an item out from within
+/// 1Password.
+pub const DEFAULT_HIDE_TAG: &str = "opbookmarks:hide";
+
+/// `[profiles.<name>.item_filter]` from the config file. Categories are `op` category names such
+/// as `LOGIN` or `CREDIT_CARD`. A tag also matches its nested tags, so `work` matches `work/dev`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ItemFilter {
+    pub include_categories: Vec<String>,
+    pub exclude_categories: Vec<String>,
+    pub include_tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+    /// Skip items without any website URLs
+    pub require_urls: bool,
+    pub hide_tag: String,
+}
+
+impl Default for ItemFilter {
+    fn default() -> Self {
+        ItemFilter {
+            include_categories: vec![],
+            exclude_categories: vec![],
+            include_tags: vec![],
+            exclude_tags: vec![],
+            require_urls: false,
+            hide_tag: DEFAULT_HIDE_TAG.to_string(),
+        }
+    }
+}
+
+impl ItemFilter {
+    /// Identifies the rules in the manifest, so vaults exported with other rules are re-exported
+    pub fn fingerprint(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        crate::manifest::checksum(&json)
+    }
+
+    pub fn is_item_exported(&self, item: &ItemOverview) -> bool {
+        let has_category = |categories: &[String]| {
+            categories
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(&item.category))
+        };
+        let has_tag = |tags: &[String]| {
+            tags.iter()
+                .any(|t| item.tags.iter().any(|item_tag| tag_matches(t, item_tag)))
+        };
+
+        if !self.hide_tag.is_empty() && item.tags.iter().any(|t| t == &self.hide_tag) {
+            return false;
+        }
+        if self.require_urls && item.urls.is_empty() {
+            return false;
+        }
+        if !self.include_categories.is_empty() && !has_category(&self.include_categories) {
+            return false;
+        }
+        if has_category(&self.exclude_categories) {
+            return false;
+        }
+        if !self.include_tags.is_empty() && !has_tag(&self.include_tags) {
+            return false;
+        }
+
+        !has_tag(&self.exclude_tags)
+    }
+}
+
+fn tag_matches(filter: &str, tag: &str) -> bool {
+    if tag.eq_ignore_ascii_case(filter) {
+        return true;
+    }
+
+    tag.len() > filter.len()
+        && tag.as_bytes()[filter.len()] == b'/'
+        && tag[..filter.len()].eq_ignore_ascii_case(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(user_uuid: &str) -> AccountCandidate {
+        AccountCandidate {
+            overview: AccountOverview {
+                email: format!("{}@example.com", user_uuid.to_lowercase()),
+                url: "my.1password.com".to_string(),
+                user_uuid: user_uuid.to_string(),
+                account_uuid: String::new(),
+            },
+            details: None,
+        }
+    }
+
+    fn details(account: &AccountOverview) -> Result<AccountDetails, Error> {
+        match account.user_uuid.as_str() {
+            "SUSPENDED" => Err(Error::OPCLI("account is suspended".to_string())),
+            user_uuid => Ok(AccountDetails {
+                id: format!("ACCT{}", user_uuid),
+                name: user_uuid.to_lowercase(),
+                domain: "my".to_string(),
+                account_type: "FAMILY".to_string(),
+                state: "ACTIVE".to_string(),
+                created_at: String::new(),
+            }),
+        }
+    }
+
+    #[test]
+    fn accounts_whose_details_fail_to_load_dont_match() {
+        let mut candidates = vec![candidate("SUSPENDED"), candidate("FAMILY")];
+        let selectors = vec!["fam*".to_string()];
+
+        let selected = select_accounts(&selectors, &mut candidates, details, false).unwrap();
+        assert_eq!(selected, vec![1]);
+    }
+
+    #[test]
+    fn accounts_whose_details_fail_to_load_fail_strict_selection() {
+        let mut candidates = vec![candidate("SUSPENDED"), candidate("FAMILY")];
+        let selectors = vec!["fam*".to_string()];
+
+        assert!(select_accounts(&selectors, &mut candidates, details, true).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+/// Removes exported vaults whose time-to-live ran out, such as after the watch daemon stopped
+use crate::cache::BookmarkCache;
+use crate::manifest::Manifest;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpiryAction {
+    Delete,
+    /// Move the files out of the export path, next to it, where they can be inspected
+    Quarantine,
+}
+
+/// `<export path>.quarantine`, outside of the export path so consumers don't read it
+pub fn quarantine_path(export_path: &Path) -> PathBuf {
+    let mut name = export_path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".quarantine");
+    export_path.with_file_name(name)
+}
+
+/// Deletes or quarantines the files of every expired vault in the manifest and forgets them in
+/// the cache so they're exported afresh by the next sync. Returns how many vaults were expired.
+pub fn collect(
+    export_path: &Path,
+    cache: &mut BookmarkCache,
+    manifest: &mut Manifest,
+    action: ExpiryAction,
+) -> Result<usize, std::io::Error> {
+    let now = crate::manifest::now();
+    let expired = manifest.expired_vaults(now);
+
+    let mut destination = quarantine_path(export_path);
+    destination.push(now.to_string());
+
+    for (account_id, vault_id) in expired.iter() {
+        let mut files = crate::op7_metadata::vault_files(export_path, account_id, vault_id)?;
+        let index = crate::encryption::index_path(export_path, account_id, vault_id);
+        if index.exists() {
+            files.push(index);
+        }
+
+        match action {
+            ExpiryAction::Delete => {
+                for file in files.iter() {
+                    std::fs::remove_file(file)?;
+                }
+                println!(
+                    "Deleted {} expired files of {}::{}",
+                    files.len(),
+                    account_id,
+                    vault_id
+                );
+            }
+            ExpiryAction::Quarantine => {
+                let mut folder = destination.clone();
+                folder.push(account_id);
+                std::fs::create_dir_all(&folder)?;
+
+                for file in files.iter() {
+                    let mut target = folder.clone();
+                    target.push(file.file_name().unwrap());
+                    std::fs::rename(file, target)?;
+                }
+                println!(
+                    "Moved {} expired files of {}::{} to {:?}",
+                    files.len(),
+                    account_id,
+                    vault_id,
+                    folder
+                );
+            }
+        }
+
+        manifest.remove_vault(account_id, vault_id);
+        cache.invalidate(Some(account_id), Some(vault_id));
+    }
+
+    Ok(expired.len())
+}
@@ -0,0 +1,466 @@
+/// Repairs files under the export path that something other than opbookmarks deleted or changed
+///
+/// Syncs only notice that a file is gone or wrong once its vault changes in 1Password. The daemon
+/// keeps what it last wrote for each vault in memory and watches the export folder, checking
+/// changed files against the checksums in the manifest. Files that no longer match are written
+/// again from memory, without calling `op`, and files no export accounts for are removed.
+use crate::lock::{self, LockPolicy};
+use crate::manifest::{self, Manifest, VaultManifest};
+use crate::resync::Drift;
+use crate::sync::{ExportedVault, Observer};
+use notify::{RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the export folder must go without changes before they're checked, so files aren't
+/// restored while whatever changed them is still at it
+const QUIET_PERIOD: Duration = Duration::from_secs(1);
+
+/// The contents of a vault's files by file name within the account's folder
+type VaultFiles = BTreeMap<String, Vec<u8>>;
+
+/// The files syncs wrote. Cheap to clone: clones share the same data.
+#[derive(Clone, Default)]
+pub struct Files(Arc<Mutex<Written>>);
+
+#[derive(Default)]
+struct Written {
+    /// By vault ID by account ID
+    vaults: HashMap<String, HashMap<String, VaultFiles>>,
+
+    /// Account and vault IDs of the vaults whose files were found not to match the manifest.
+    /// Every sync checks them again until they're exported, but it's only logged once.
+    mismatched: HashSet<(String, String)>,
+}
+
+impl Files {
+    pub fn set_vault(&self, account_id: &str, vault_id: &str, files: VaultFiles) {
+        let mut written = self.0.lock().unwrap();
+        let key = (account_id.to_string(), vault_id.to_string());
+        written.mismatched.remove(&key);
+        written
+            .vaults
+            .entry(key.0)
+            .or_default()
+            .insert(key.1, files);
+    }
+
+    /// Reads a vault's files from the export folder when they aren't in memory yet, such as for
+    /// vaults the daemon's first sync found unchanged. Returns false when they don't match the
+    /// manifest, so the vault has to be exported again.
+    pub fn load_vault(
+        &self,
+        export_path: &Path,
+        account_id: &str,
+        vault_id: &str,
+        exported: Option<&VaultManifest>,
+    ) -> bool {
+        if matches!(self.0.lock().unwrap().vaults.get(account_id), Some(vaults) if vaults.contains_key(vault_id))
+        {
+            return true;
+        }
+        let exported = match exported {
+            Some(exported) => exported,
+            None => return false,
+        };
+        // Manifests from before checksums were recorded can't vouch for the files
+        if exported.checksums.is_empty()
+            && (exported.items > 0
+                || exported
+                    .formats
+                    .contains(&crate::encryption::ExportFormat::Age))
+        {
+            return false;
+        }
+
+        let mut files = BTreeMap::new();
+        for (name, checksum) in exported.checksums.iter() {
+            let path = file_path(export_path, account_id, name);
+            match std::fs::read(&path) {
+                Ok(contents) if manifest::checksum(&contents) == *checksum => {
+                    files.insert(name.clone(), contents);
+                }
+                _ => {
+                    let key = (account_id.to_string(), vault_id.to_string());
+                    if self.0.lock().unwrap().mismatched.insert(key) {
+                        eprintln!(
+                            "{:?} doesn't match the manifest, exporting {}::{} again",
+                            path, account_id, vault_id
+                        );
+                    }
+                    return false;
+                }
+            }
+        }
+        self.set_vault(account_id, vault_id, files);
+        true
+    }
+
+    pub fn remove_vault(&self, account_id: &str, vault_id: &str) {
+        let mut written = self.0.lock().unwrap();
+        written
+            .mismatched
+            .remove(&(account_id.to_string(), vault_id.to_string()));
+        if let Some(vaults) = written.vaults.get_mut(account_id) {
+            vaults.remove(vault_id);
+        }
+    }
+
+    /// What was written to the file, if it's what the manifest has a checksum of
+    fn get(&self, account_id: &str, vault_id: &str, name: &str, checksum: &str) -> Option<Vec<u8>> {
+        let files = self.0.lock().unwrap();
+        let contents = files.vaults.get(account_id)?.get(vault_id)?.get(name)?;
+        match manifest::checksum(contents) == checksum {
+            true => Some(contents.clone()),
+            false => None,
+        }
+    }
+}
+
+/// Keeps what each sync wrote, or found already written
+impl Observer for Files {
+    fn vault_removed(&mut self, account_id: &str, vault_id: &str, _count: usize) {
+        self.remove_vault(account_id, vault_id);
+    }
+
+    fn needs_export(
+        &mut self,
+        export_path: &Path,
+        account_id: &str,
+        vault_id: &str,
+        exported: Option<&VaultManifest>,
+    ) -> bool {
+        !self.load_vault(export_path, account_id, vault_id, exported)
+    }
+
+    fn exported(
+        &mut self,
+        account: &crate::op::AccountDetails,
+        vault: &crate::op::VaultDetails,
+        exported: &ExportedVault,
+    ) -> Result<(), String> {
+        self.set_vault(&account.id, &vault.id, exported.files.clone());
+        Ok(())
+    }
+}
+
+/// Watches the export folder and repairs the files that changed
+pub struct Healer {
+    // Events stop when the watcher is dropped
+    _watcher: RecommendedWatcher,
+    rx: Receiver<RawEvent>,
+    export_path: PathBuf,
+    files: Files,
+    changed: BTreeSet<PathBuf>,
+    last_change: Option<Instant>,
+}
+
+impl Healer {
+    pub fn new(export_path: &Path, files: Files) -> notify::Result<Healer> {
+        std::fs::create_dir_all(export_path)?;
+        let (tx, rx) = channel();
+        let mut watcher = notify::raw_watcher(tx)?;
+        watcher.watch(export_path, RecursiveMode::Recursive)?;
+
+        Ok(Healer {
+            _watcher: watcher,
+            rx,
+            export_path: export_path.to_path_buf(),
+            files,
+            changed: BTreeSet::new(),
+            last_change: None,
+        })
+    }
+
+    /// Repairs the files that changed once the export folder has settled. Doesn't block: changes
+    /// are left for a later call while they're still coming in or another process holds the
+    /// export lock, such as a sync run by hand. Returns an error once the watcher has stopped.
+    pub fn check(&mut self) -> notify::Result<Drift> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(RawEvent {
+                    path: Some(path), ..
+                }) if self.is_exported(&path) => {
+                    self.changed.insert(path);
+                    self.last_change = Some(Instant::now());
+                }
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return Err(notify::Error::Generic(
+                        "the export folder watcher stopped".to_string(),
+                    ))
+                }
+            }
+        }
+
+        let settled = matches!(self.last_change, Some(last) if last.elapsed() >= QUIET_PERIOD);
+        if !settled {
+            return Ok(Drift::default());
+        }
+        let _lock = match lock::acquire(&self.export_path, LockPolicy::Fail) {
+            Ok(lock) => lock,
+            Err(_) => return Ok(Drift::default()),
+        };
+
+        let changed = std::mem::take(&mut self.changed);
+        self.last_change = None;
+        let manifest = manifest::load(&self.export_path);
+        let mut drift = Drift::default();
+        for path in changed.iter() {
+            // Deleting or moving an account's folder may only report the folder itself
+            let account = path
+                .strip_prefix(&self.export_path)
+                .ok()
+                .and_then(|relative| relative.to_str())
+                .and_then(|account_id| Some((account_id, manifest.accounts.get(account_id)?)));
+            match account {
+                Some((account_id, vaults)) => {
+                    for name in vaults.values().flat_map(|v| v.checksums.keys()) {
+                        let path = file_path(&self.export_path, account_id, name);
+                        repair(&self.export_path, &path, &manifest, &self.files, &mut drift);
+                    }
+                }
+                None => repair(&self.export_path, path, &manifest, &self.files, &mut drift),
+            }
+        }
+        Ok(drift)
+    }
+
+    /// Account folders and the files in them. The manifest is left out, as is the lock file older
+    /// versions kept in the export folder.
+    fn is_exported(&self, path: &Path) -> bool {
+        match path.strip_prefix(&self.export_path) {
+            Ok(relative) => match relative.iter().count() {
+                1 => {
+                    path != lock::legacy_lock_path(&self.export_path)
+                        && path != manifest::manifest_path(&self.export_path)
+                }
+                2 => true,
+                _ => false,
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// Restores or removes a file in an account's folder, logging what was done
+fn repair(export_path: &Path, path: &Path, manifest: &Manifest, files: &Files, drift: &mut Drift) {
+    let (account_id, name) = match path.strip_prefix(export_path).ok().and_then(split) {
+        Some(file) => file,
+        None => return,
+    };
+    let vaults = match manifest.accounts.get(account_id) {
+        Some(vaults) => vaults,
+        None => return,
+    };
+
+    let expected = vaults
+        .iter()
+        .find_map(|(vault_id, v)| Some((vault_id, v.checksums.get(name)?)));
+    let current = std::fs::read(path).ok();
+    match expected {
+        Some((vault_id, checksum)) => {
+            if matches!(&current, Some(contents) if manifest::checksum(contents) == *checksum) {
+                return;
+            }
+            let contents = match files.get(account_id, vault_id, name, checksum) {
+                Some(contents) => contents,
+                None => {
+                    eprintln!(
+                        "Unable to restore {:?} as what was exported isn't known, leaving it for the next sync of {}::{}",
+                        path, account_id, vault_id
+                    );
+                    return;
+                }
+            };
+            if let Err(e) = restore(path, &contents) {
+                eprintln!("Unable to restore {:?}: {}", path, e);
+                return;
+            }
+            match current {
+                Some(_) => {
+                    println!("Restored modified file {:?}", path);
+                    drift.modified += 1;
+                }
+                None => {
+                    println!("Restored deleted file {:?}", path);
+                    drift.missing += 1;
+                }
+            }
+        }
+        None => {
+            // Only files of vaults whose every file has a checksum can be told apart from strays
+            let vault = vaults.get(vault_id_of(name).unwrap_or_default());
+            let is_stray = current.is_some() && matches!(vault, Some(v) if !v.checksums.is_empty());
+            if !is_stray {
+                return;
+            }
+            match std::fs::remove_file(path) {
+                Ok(_) => {
+                    println!("Removed stray file {:?}", path);
+                    drift.stray += 1;
+                }
+                Err(e) => eprintln!("Unable to remove stray file {:?}: {}", path, e),
+            }
+        }
+    }
+}
+
+/// The account ID and file name of a file in an account's folder
+fn split(relative: &Path) -> Option<(&str, &str)> {
+    let mut components = relative.iter();
+    let account_id = components.next()?.to_str()?;
+    let name = components.next()?.to_str()?;
+    match components.next() {
+        Some(_) => None,
+        None => Some((account_id, name)),
+    }
+}
+
+/// The vault of a metadata file or an index, going by its name
+fn vault_id_of(name: &str) -> Option<&str> {
+    if let Some(vault_id) = name.strip_suffix(&format!(".{}", crate::encryption::INDEX_EXTENSION)) {
+        return Some(vault_id);
+    }
+    let stem = name.strip_suffix(&format!(".{}", crate::op7_metadata::METADATA_EXTENSION))?;
+    Some(stem.split_once('_')?.0)
+}
+
+fn restore(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    match crate::encryption::is_index(path) {
+        true => crate::util::write_private_file(path, contents),
+        false => crate::util::write_file(path.to_path_buf(), contents),
+    }
+}
+
+fn file_path(export_path: &Path, account_id: &str, name: &str) -> PathBuf {
+    let mut path = export_path.to_path_buf();
+    path.push(account_id);
+    path.push(name);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::ExportFormat;
+    use crate::privacy::PrivacyLevel;
+    use crate::test_support::TempDir;
+
+    const NAME: &str = "v1_i1.onepassword-item-metadata";
+
+    /// An export of vault `v1` of account `A` with a single file, and the files the daemon kept
+    fn export(dir: &TempDir) -> (Manifest, Files) {
+        let written = BTreeMap::from([(NAME.to_string(), b"exported".to_vec())]);
+        let mut manifest = Manifest::default();
+        manifest
+            .accounts
+            .entry("A".to_string())
+            .or_default()
+            .insert(
+                "v1".to_string(),
+                VaultManifest::new(
+                    PrivacyLevel::Full,
+                    &[ExportFormat::Json],
+                    String::new(),
+                    None,
+                    1,
+                    None,
+                    &written,
+                ),
+            );
+        let files = Files::default();
+        files.set_vault("A", "v1", written);
+        std::fs::create_dir_all(dir.file("A")).unwrap();
+        (manifest, files)
+    }
+
+    #[test]
+    fn files_are_split_into_account_and_name() {
+        assert_eq!(split(Path::new("A/file")), Some(("A", "file")));
+        assert_eq!(split(Path::new("A")), None);
+        assert_eq!(split(Path::new("A/v1/file")), None);
+    }
+
+    #[test]
+    fn vaults_are_found_from_file_names() {
+        assert_eq!(vault_id_of(NAME), Some("v1"));
+        assert_eq!(vault_id_of("v1.opbookmarks.age"), Some("v1"));
+        assert_eq!(vault_id_of("v1.onepassword-item-metadata"), None);
+        assert_eq!(vault_id_of("notes.txt"), None);
+    }
+
+    #[test]
+    fn deleted_and_modified_files_are_restored() {
+        let dir = TempDir::new("heal");
+        let (manifest, files) = export(&dir);
+        let path = dir.file("A").join(NAME);
+        let mut drift = Drift::default();
+
+        repair(&dir.0, &path, &manifest, &files, &mut drift);
+        assert_eq!(std::fs::read(&path).unwrap(), b"exported");
+        assert_eq!(drift.missing, 1);
+
+        std::fs::write(&path, b"edited by hand").unwrap();
+        repair(&dir.0, &path, &manifest, &files, &mut drift);
+        assert_eq!(std::fs::read(&path).unwrap(), b"exported");
+        assert_eq!(drift.modified, 1);
+
+        repair(&dir.0, &path, &manifest, &files, &mut drift);
+        assert_eq!(drift.total(), 2);
+    }
+
+    #[test]
+    fn files_are_left_when_what_was_exported_isnt_known() {
+        let dir = TempDir::new("heal");
+        let (manifest, _) = export(&dir);
+        let path = dir.file("A").join(NAME);
+        let mut drift = Drift::default();
+
+        repair(&dir.0, &path, &manifest, &Files::default(), &mut drift);
+        assert!(!path.exists());
+        assert_eq!(drift.total(), 0);
+    }
+
+    #[test]
+    fn only_strays_of_vaults_with_checksums_are_removed() {
+        let dir = TempDir::new("heal");
+        let (manifest, files) = export(&dir);
+        let stray = dir.file("A").join("v1_i9.onepassword-item-metadata");
+        let unknown = dir.file("A").join("v9_i9.onepassword-item-metadata");
+        std::fs::write(&stray, b"{}").unwrap();
+        std::fs::write(&unknown, b"{}").unwrap();
+        let mut drift = Drift::default();
+
+        repair(&dir.0, &stray, &manifest, &files, &mut drift);
+        repair(&dir.0, &unknown, &manifest, &files, &mut drift);
+        assert!(!stray.exists());
+        assert!(unknown.exists());
+        assert_eq!(drift.stray, 1);
+    }
+
+    #[test]
+    fn vaults_are_loaded_from_files_matching_the_manifest() {
+        let dir = TempDir::new("heal");
+        let (manifest, _) = export(&dir);
+        let exported = manifest.accounts["A"].get("v1");
+        let path = dir.file("A").join(NAME);
+        let files = Files::default();
+
+        std::fs::write(&path, b"edited by hand").unwrap();
+        assert!(!files.load_vault(&dir.0, "A", "v1", exported));
+        assert!(!files.load_vault(&dir.0, "A", "v1", exported));
+        assert_eq!(files.0.lock().unwrap().mismatched.len(), 1);
+
+        std::fs::write(&path, b"exported").unwrap();
+        assert!(files.load_vault(&dir.0, "A", "v1", exported));
+        assert!(files.0.lock().unwrap().mismatched.is_empty());
+        assert!(files
+            .get("A", "v1", NAME, &manifest::checksum(b"exported"))
+            .is_some());
+    }
+}
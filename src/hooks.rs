@@ -0,0 +1,321 @@
+/// Runs the commands configured to be told about each sync, such as reloading a launcher's cache
+///
+/// Hooks run after the cache and manifest are saved and the export lock is released, so a hook
+/// can run opbookmarks itself and a failing hook can't leave the export half written.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+
+/// Seconds
+pub const DEFAULT_TIMEOUT: u64 = 30;
+
+/// A `[[profiles.<name>.hooks]]` entry from the config file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// Run with `/bin/sh -c`
+    pub command: String,
+    /// Such as `"30s"` or `"2m"`
+    pub timeout: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Hook {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl Hook {
+    pub fn new(command: &str) -> Hook {
+        Hook {
+            command: command.to_string(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT),
+        }
+    }
+
+    pub fn parse(config: &HookConfig) -> Result<Hook, String> {
+        let timeout = match &config.timeout {
+            Some(timeout) => crate::util::parse_duration(timeout)?,
+            None => DEFAULT_TIMEOUT,
+        };
+        Ok(Hook {
+            command: config.command.clone(),
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+
+    /// The program's file name, to label its output in the log
+    fn name(&self) -> &str {
+        let program = self.command.split_whitespace().next().unwrap_or("");
+        program.rsplit('/').next().unwrap_or(program)
+    }
+}
+
+/// Items of a vault whose exported files were written or removed by a sync
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VaultChanges {
+    pub account_id: String,
+    pub vault_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault_name: Option<String>,
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Compares item versions, by item ID, by vault ID, by account ID, from before and after a sync.
+/// Vaults without any changes are left out.
+pub fn changes(
+    before: &HashMap<String, HashMap<String, BTreeMap<String, usize>>>,
+    after: &HashMap<String, HashMap<String, BTreeMap<String, usize>>>,
+    vault_names: &HashMap<(String, String), String>,
+) -> Vec<VaultChanges> {
+    let empty = BTreeMap::new();
+    let mut vaults: Vec<(&String, &String)> = before
+        .iter()
+        .chain(after.iter())
+        .flat_map(|(account_id, vaults)| vaults.keys().map(move |vault_id| (account_id, vault_id)))
+        .collect();
+    vaults.sort();
+    vaults.dedup();
+
+    let mut all = vec![];
+    for (account_id, vault_id) in vaults {
+        let old = before
+            .get(account_id)
+            .and_then(|v| v.get(vault_id))
+            .unwrap_or(&empty);
+        let new = after
+            .get(account_id)
+            .and_then(|v| v.get(vault_id))
+            .unwrap_or(&empty);
+
+        let vault = VaultChanges {
+            account_id: account_id.clone(),
+            vault_id: vault_id.clone(),
+            vault_name: vault_names
+                .get(&(account_id.clone(), vault_id.clone()))
+                .cloned(),
+            added: new
+                .keys()
+                .filter(|id| !old.contains_key(*id))
+                .cloned()
+                .collect(),
+            changed: new
+                .iter()
+                .filter(|(id, version)| matches!(old.get(*id), Some(old) if old != *version))
+                .map(|(id, _)| id.clone())
+                .collect(),
+            removed: old
+                .keys()
+                .filter(|id| !new.contains_key(*id))
+                .cloned()
+                .collect(),
+        };
+        if !vault.added.is_empty() || !vault.changed.is_empty() || !vault.removed.is_empty() {
+            all.push(vault);
+        }
+    }
+    all
+}
+
+/// What hooks receive on stdin
+#[derive(Debug, Serialize)]
+pub struct Summary<'a> {
+    pub export_path: &'a Path,
+    pub profile: Option<&'a str>,
+    /// Unix time the sync finished
+    pub synced_at: u64,
+    pub full: bool,
+    /// `ok`, `partial` when some vaults failed, `signed-out` when `op` isn't signed in to any
+    /// account or `failed` when nothing was exported
+    pub status: &'a str,
+    pub vaults: &'a [VaultChanges],
+    pub errors: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+    /// What a full sync of every account and vault repaired in the export folder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drift: Option<&'a crate::resync::Drift>,
+}
+
+/// Runs each hook in turn. Failures are logged and don't stop the other hooks.
+pub fn run_all(hooks: &[Hook], summary: &Summary) {
+    if hooks.is_empty() {
+        return;
+    }
+
+    let json = match serde_json::to_string(summary) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Unable to serialize sync summary for hooks: {}", e);
+            return;
+        }
+    };
+
+    let manifest = crate::manifest::manifest_path(summary.export_path);
+    let env = [
+        ("OPBOOKMARKS_EXPORT_PATH", summary.export_path.as_os_str()),
+        ("OPBOOKMARKS_MANIFEST", manifest.as_os_str()),
+        (
+            "OPBOOKMARKS_PROFILE",
+            OsStr::new(summary.profile.unwrap_or("")),
+        ),
+        ("OPBOOKMARKS_SYNC_STATUS", OsStr::new(summary.status)),
+    ];
+
+    for hook in hooks.iter() {
+        crate::systemd::watchdog();
+        let started = Instant::now();
+        match run(hook, &json, &env) {
+            Ok(Some(status)) if status.success() => println!(
+                "Hook {} finished in {}ms",
+                hook.name(),
+                started.elapsed().as_millis()
+            ),
+            Ok(Some(status)) => eprintln!("Hook {} failed: {}", hook.name(), status),
+            Ok(None) => eprintln!(
+                "Hook {} was killed after running for {}s",
+                hook.name(),
+                hook.timeout.as_secs()
+            ),
+            Err(e) => eprintln!("Unable to run hook {:?}: {}", hook.command, e),
+        }
+    }
+}
+
+/// Runs a hook with the summary on stdin, logging its output. Returns `None` when it timed out.
+fn run(
+    hook: &Hook,
+    json: &str,
+    env: &[(&str, &OsStr)],
+) -> std::io::Result<Option<std::process::ExitStatus>> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&hook.command)
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so a timeout kills whatever the shell started too
+        .process_group(0)
+        .spawn()?;
+
+    // Written from a thread so a hook that doesn't read its input can't block the daemon
+    if let Some(mut stdin) = child.stdin.take() {
+        let json = json.to_string();
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(json.as_bytes());
+        });
+    }
+
+    let (done, output_closed) = channel();
+    let name = hook.name().to_string();
+    let log = |output: Option<Box<dyn Read + Send>>, is_stderr: bool| {
+        let done = done.clone();
+        let name = name.clone();
+        std::thread::spawn(move || {
+            if let Some(output) = output {
+                for line in BufReader::new(output).lines().map_while(Result::ok) {
+                    if is_stderr {
+                        eprintln!("[{}] {}", name, line);
+                    } else {
+                        println!("[{}] {}", name, line);
+                    }
+                }
+            }
+            let _ = done.send(());
+        });
+    };
+    log(
+        child
+            .stdout
+            .take()
+            .map(|o| Box::new(o) as Box<dyn Read + Send>),
+        false,
+    );
+    log(
+        child
+            .stderr
+            .take()
+            .map(|o| Box::new(o) as Box<dyn Read + Send>),
+        true,
+    );
+
+    let deadline = Instant::now() + hook.timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            let _ = child.wait();
+            break None;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+
+    // Programs the hook left running in the background may hold on to its output
+    for _ in 0..2 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if output_closed
+            .recv_timeout(std::cmp::max(remaining, Duration::from_millis(100)))
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::item_versions as versions;
+
+    #[test]
+    fn items_are_sorted_into_added_changed_and_removed() {
+        let before = versions(&[("A", "v1", &[("i1", 1), ("i2", 1), ("i3", 1)])]);
+        let after = versions(&[("A", "v1", &[("i1", 2), ("i3", 1), ("i4", 1)])]);
+        let names = HashMap::from([(("A".to_string(), "v1".to_string()), "Private".to_string())]);
+
+        let changes = changes(&before, &after, &names);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].vault_name.as_deref(), Some("Private"));
+        assert_eq!(changes[0].added, vec!["i4"]);
+        assert_eq!(changes[0].changed, vec!["i1"]);
+        assert_eq!(changes[0].removed, vec!["i2"]);
+    }
+
+    #[test]
+    fn vaults_only_on_one_side_are_all_added_or_removed() {
+        let before = versions(&[("A", "v1", &[("i1", 1)]), ("A", "v2", &[("i2", 1)])]);
+        let after = versions(&[("A", "v2", &[("i2", 1)]), ("B", "v3", &[("i3", 1)])]);
+
+        let changes = changes(&before, &after, &HashMap::new());
+        let summary: Vec<(&str, &str, &[String], &[String])> = changes
+            .iter()
+            .map(|c| {
+                (
+                    c.account_id.as_str(),
+                    c.vault_id.as_str(),
+                    &c.added[..],
+                    &c.removed[..],
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("A", "v1", &[][..], &["i1".to_string()][..]),
+                ("B", "v3", &["i3".to_string()][..], &[][..]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+/// Advisory lock per export folder so a watch daemon and manual runs don't write at the same time
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Where versions before the move to the cache folder kept the lock: in the export folder
+const LEGACY_LOCK_FILE_NAME: &str = ".opbookmarks.lock";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockPolicy {
+    /// Give up straight away when another process holds the lock
+    Fail,
+    /// Wait for the lock to be released, optionally giving up after a timeout
+    Wait(Option<Duration>),
+}
+
+impl LockPolicy {
+    /// Maps `--lock-timeout` to a policy: no value waits forever and 0 fails immediately.
+    pub fn from_timeout(seconds: Option<u64>) -> LockPolicy {
+        match seconds {
+            None => LockPolicy::Wait(None),
+            Some(0) => LockPolicy::Fail,
+            Some(s) => LockPolicy::Wait(Some(Duration::from_secs(s))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(std::io::Error),
+    Locked(Option<u32>),
+    Timeout(Option<u32>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "unable to lock export folder: {}", e),
+            Error::Locked(pid) => write!(f, "export folder is locked by {}", describe(pid)),
+            Error::Timeout(pid) => write!(
+                f,
+                "timed out waiting for {} to release the export folder lock",
+                describe(pid)
+            ),
+        }
+    }
+}
+
+fn describe(pid: &Option<u32>) -> String {
+    match pid {
+        Some(pid) => format!("PID {}", pid),
+        None => "another process".to_string(),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LockState {
+    Unlocked,
+    Held(Option<u32>),
+    /// The lock file names a process that is no longer running. When `held` is true the lock is
+    /// still taken, most likely by a child process that inherited the descriptor. This can only
+    /// be told apart where `/proc/locks` is available.
+    Stale {
+        pid: u32,
+        held: bool,
+    },
+}
+
+/// Held for as long as this value lives. Dropping it releases the lock.
+pub struct ExportLock {
+    file: File,
+}
+
+impl Drop for ExportLock {
+    fn drop(&mut self) {
+        // Clear our PID so it isn't mistaken for a stale owner. Closing the file releases the
+        // flock; the file itself is left in place as removing it would race with waiters.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Kept beside the cache, one per export path, so nothing but exported bookmarks ends up in the
+/// export folder.
+pub fn lock_path(export_path: &Path) -> PathBuf {
+    let mut path = crate::cache::cache_dir();
+    path.push(format!(
+        "lock-{:016x}",
+        crate::util::stable_hash(export_path.as_os_str().to_string_lossy().as_bytes())
+    ));
+    path
+}
+
+pub fn legacy_lock_path(export_path: &Path) -> PathBuf {
+    let mut path = export_path.to_path_buf();
+    path.push(LEGACY_LOCK_FILE_NAME);
+    path
+}
+
+pub fn acquire(export_path: &Path, policy: LockPolicy) -> Result<ExportLock, Error> {
+    std::fs::create_dir_all(crate::cache::cache_dir()).map_err(Error::IO)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // The previous owner's PID is needed to report stale locks
+        .truncate(false)
+        .open(lock_path(export_path))
+        .map_err(Error::IO)?;
+
+    let started = Instant::now();
+    let mut reported_wait = false;
+
+    loop {
+        if try_flock(&file).map_err(Error::IO)? {
+            break;
+        }
+
+        let pid = read_pid(&mut file);
+        if let Some(pid) = pid {
+            if !is_running(pid) && !reported_wait {
+                eprintln!(
+                    "Export folder lock is held but its owner PID {} is no longer running. A process it started may still have the lock open.",
+                    pid
+                );
+            }
+        }
+
+        match policy {
+            LockPolicy::Fail => return Err(Error::Locked(pid)),
+            LockPolicy::Wait(timeout) => {
+                if let Some(timeout) = timeout {
+                    if started.elapsed() >= timeout {
+                        return Err(Error::Timeout(pid));
+                    }
+                }
+
+                if !reported_wait {
+                    println!(
+                        "Waiting for {} to release the export folder lock...",
+                        describe(&pid)
+                    );
+                    reported_wait = true;
+                }
+                std::thread::sleep(Duration::from_millis(250));
+            }
+        }
+    }
+
+    if let Some(pid) = read_pid(&mut file) {
+        if pid != std::process::id() {
+            eprintln!(
+                "Removing stale export folder lock left behind by PID {}",
+                pid
+            );
+        }
+    }
+
+    file.set_len(0).map_err(Error::IO)?;
+    file.seek(SeekFrom::Start(0)).map_err(Error::IO)?;
+    write!(file, "{}", std::process::id()).map_err(Error::IO)?;
+    file.flush().map_err(Error::IO)?;
+
+    remove_legacy_lock(export_path);
+
+    Ok(ExportLock { file })
+}
+
+/// Removes the lock file older versions left in the export folder, unless one of them still
+/// holds it.
+fn remove_legacy_lock(export_path: &Path) {
+    let path = legacy_lock_path(export_path);
+    let mut file = match OpenOptions::new().read(true).open(&path) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+
+    match read_pid(&mut file) {
+        Some(pid) if is_running(pid) => (),
+        _ => {
+            if let Err(e) = std::fs::remove_file(&path) {
+                eprintln!("Unable to remove old lock file {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Reports who, if anyone, holds the lock. The lock is never taken, not even briefly, so this
+/// can't make a concurrent run fail.
+pub fn state(export_path: &Path) -> Result<LockState, std::io::Error> {
+    let path = lock_path(export_path);
+    if !path.exists() {
+        return Ok(LockState::Unlocked);
+    }
+
+    let mut file = OpenOptions::new().read(true).open(path)?;
+    let pid = read_pid(&mut file);
+
+    Ok(match (is_flocked(&file)?, pid) {
+        (Some(true), Some(pid)) if !is_running(pid) => LockState::Stale { pid, held: true },
+        (Some(true), pid) => LockState::Held(pid),
+        (Some(false), Some(pid)) if !is_running(pid) => LockState::Stale { pid, held: false },
+        (Some(false), _) => LockState::Unlocked,
+        // Without a way to look at the lock, go by the PID, which is cleared on release
+        (None, Some(pid)) if is_running(pid) => LockState::Held(Some(pid)),
+        (None, Some(pid)) => LockState::Stale { pid, held: false },
+        (None, None) => LockState::Unlocked,
+    })
+}
+
+/// Looks the file up in `/proc/locks`, which lists every flock held without taking part in them.
+/// Waiters are listed too, after a `->`, and are skipped.
+#[cfg(target_os = "linux")]
+fn is_flocked(file: &File) -> Result<Option<bool>, std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let locks = match std::fs::read_to_string("/proc/locks") {
+        Ok(locks) => locks,
+        Err(_) => return Ok(None),
+    };
+
+    let metadata = file.metadata()?;
+    let dev = metadata.dev();
+    let id = unsafe {
+        format!(
+            "{:02x}:{:02x}:{}",
+            libc::major(dev),
+            libc::minor(dev),
+            metadata.ino()
+        )
+    };
+
+    Ok(Some(locks.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.get(1) == Some(&"FLOCK") && fields.get(5) == Some(&id.as_str())
+    })))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_flocked(_file: &File) -> Result<Option<bool>, std::io::Error> {
+    Ok(None)
+}
+
+fn try_flock(file: &File) -> Result<bool, std::io::Error> {
+    let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if result == 0 {
+        return Ok(true);
+    }
+
+    let err = std::io::Error::last_os_error();
+    if err.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+pub fn is_running(pid: u32) -> bool {
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
@@ -1,91 +1,522 @@
+mod bookmarks;
+mod cache;
+mod config;
+mod control;
+mod daemon;
+mod encryption;
+mod events;
+mod filter;
+mod gc;
+mod heal;
+mod hooks;
+mod lock;
+mod manifest;
 mod op;
 mod op7_metadata;
+mod platform;
+mod poll;
+mod privacy;
+mod purge;
+mod resync;
+mod serve;
+mod sync;
+mod systemd;
+#[cfg(test)]
+mod test_support;
+mod triggers;
 mod util;
+mod watch;
 
-use op::{load_all_accounts, load_all_vaults, AccountDetails, VaultDetails};
-use op7_metadata::write_items;
-
-use clap::Parser;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use clap::{Args, Parser, Subcommand};
+use lock::LockState;
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::mpsc::channel;
-use std::time::Duration;
-use std::{collections::HashMap, process::exit};
-
-use crate::op::ItemOverview;
+use std::process::exit;
 
 #[derive(Parser)]
 struct Cli {
-    /// Account user UUIDs to generate metadata for. Leave empty to export bookmarks for all accounts. Use spaces to separate multiple accounts. UUIDs can be found using `op account list`.
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    global: GlobalArgs,
+
+    /// Accounts to generate metadata for when no subcommand is given. Same as `sync`.
     accounts: Vec<String>,
 
+    /// Same as the `watch` subcommand. Kept for compatibility.
+    #[clap(short, long, hide = true)]
+    watch: bool,
+
+    #[clap(parse(from_os_str), long, hide = true)]
+    watch_path: Option<PathBuf>,
+}
+
+// Options shared by every subcommand
+#[derive(Args)]
+struct GlobalArgs {
+    /// The configuration file to use. Defaults to ~/.config/opbookmarks/config.toml.
+    #[clap(parse(from_os_str), long, global = true)]
+    config: Option<PathBuf>,
+
+    /// The configuration profile to use. Defaults to `default_profile` from the configuration file.
+    #[clap(short, long, global = true)]
+    profile: Option<String>,
+
     /// The path to export the metadata files to. Defaults to ~/.config/op/bookmarks.
-    #[clap(parse(from_os_str), short, long)]
+    #[clap(parse(from_os_str), short, long, global = true)]
     export_path: Option<PathBuf>,
 
-    /// Watch the 1Password data folder for changes.
-    #[clap(short, long)]
-    watch: bool,
+    /// Limit to these accounts. Can be repeated. Used in addition to any accounts given as arguments. Accepts the same selectors as `sync`.
+    #[clap(short, long, global = true, multiple_occurrences = true)]
+    account: Vec<String>,
+
+    /// Fail when an account selector doesn't match any account instead of skipping it.
+    #[clap(long, global = true)]
+    strict_accounts: bool,
+
+    /// Delete the exported bookmarks of accounts that are no longer in `op account list` or are suspended.
+    #[clap(long, global = true)]
+    auto_purge: bool,
+
+    /// Only export vaults matching this selector: a vault ID, name glob, `id:<id>`, `name:<glob>` or `type:<type>` such as `type:PERSONAL`. Can be repeated.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    include_vault: Vec<String>,
+
+    /// Never export vaults matching this selector, for example `type:EVERYONE`. Can be repeated. Excluded vaults have their exported files removed.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    exclude_vault: Vec<String>,
+
+    /// Only export items in this category, such as LOGIN. Can be repeated.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    include_category: Vec<String>,
+
+    /// Never export items in this category, such as CREDIT_CARD. Can be repeated.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    exclude_category: Vec<String>,
+
+    /// Only export items with this tag or one nested under it. Can be repeated.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    include_tag: Vec<String>,
+
+    /// Never export items with this tag or one nested under it. Can be repeated. Items tagged `opbookmarks:hide` are always skipped.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    exclude_tag: Vec<String>,
+
+    /// Only export items that have at least one website URL.
+    #[clap(long, global = true)]
+    require_urls: bool,
+
+    /// Privacy level for every vault, overriding the configured levels: `full`, `domains` (URLs reduced to their domain, no descriptions), `hashed` (as `domains` with keyed title hashes) or `ids-only` (IDs and a deep link only).
+    #[clap(long, global = true)]
+    privacy: Option<privacy::PrivacyLevel>,
+
+    /// Export format: `json` for a plain file per item, or `age` for an encrypted index per vault. Can be repeated to write both. Defaults to `json`.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    format: Vec<encryption::ExportFormat>,
+
+    /// Encrypt `age` exports to this age X25519 public key instead of the configured recipients or passphrase. Can be repeated.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    age_recipient: Vec<String>,
+
+    /// Seconds to wait for another opbookmarks process writing to the export path to finish. Use 0 to fail immediately. Waits indefinitely by default.
+    #[clap(long, global = true)]
+    lock_timeout: Option<u64>,
+
+    /// How long exported bookmarks stay valid without a sync, in seconds or with a unit such as `12h` or `7d`. Recorded in the manifest for consumers and enforced by `gc`.
+    #[clap(long, global = true, parse(try_from_str = util::parse_duration))]
+    ttl: Option<u64>,
+
+    /// A shell command to run after each sync, with a JSON summary of the changed items on stdin. Can be repeated. Replaces the configured hooks.
+    #[clap(long, global = true, multiple_occurrences = true)]
+    hook: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Export bookmark metadata for changed vaults. This is the default when no subcommand is given.
+    Sync {
+        /// Accounts to generate metadata for. Leave empty to export bookmarks for all accounts. Each may be a user UUID, account ID, email, sign-in address such as `my.1password.com` or `my`, account name, or a glob like `*@example.com`. Use spaces to separate multiple accounts.
+        accounts: Vec<String>,
+    },
+
+    /// Sync and then keep watching the 1Password data folder for changes.
+    Watch(WatchArgs),
+
+    /// Watch as `watch` does and serve the exported accounts, vaults and items as JSON over HTTP on 127.0.0.1.
+    Serve {
+        #[clap(flatten)]
+        watch: WatchArgs,
+
+        /// The port to listen on. Defaults to 8123.
+        #[clap(long)]
+        port: Option<u16>,
+
+        /// Where to keep the bearer token clients need. Defaults to a file named after the export path in the cache folder.
+        #[clap(parse(from_os_str), long)]
+        token_file: Option<PathBuf>,
+    },
 
-    /// The path to the 1Password 8 database folder to watch. Defaults to ~/Library/Group\ Containers/2BUA8C4S2C.com.1password/Library/Application\ Support/1Password/Data
+    /// Control a running `watch` daemon.
+    Ctl {
+        #[clap(subcommand)]
+        command: CtlCommand,
+
+        /// The daemon's control socket, if it isn't the default for the export path.
+        #[clap(parse(from_os_str), long, global = true)]
+        control_socket: Option<PathBuf>,
+
+        /// Print the daemon's JSON response instead of a summary.
+        #[clap(long, global = true)]
+        json: bool,
+    },
+
+    /// List the exported items. Accounts are selected the same way as for `sync`.
+    List {
+        /// Only list items in the vault with this ID or name.
+        #[clap(long)]
+        vault: Option<String>,
+    },
+
+    /// Search the titles and URLs of the exported items.
+    Search {
+        /// Text to look for, ignoring case.
+        query: String,
+    },
+
+    /// Open an exported item in 1Password.
+    Open {
+        /// The item's UUID or title.
+        item: String,
+
+        /// Open the item for editing instead of viewing it.
+        #[clap(long)]
+        edit: bool,
+    },
+
+    /// Show the state of the export path, lock and cache.
+    Status,
+
+    /// Delete or quarantine exported vaults whose time-to-live has run out. Also run when `watch` starts.
+    Gc {
+        /// Move expired files next to the export path instead of deleting them.
+        #[clap(long)]
+        quarantine: bool,
+    },
+
+    /// Delete the exported bookmarks, cache and manifest entries of the accounts given with `--account`.
+    Purge {
+        /// Purge every account in the export path.
+        #[clap(long)]
+        all: bool,
+    },
+
+    /// Print the items in encrypted `age` exports as JSON, for debugging.
+    Decrypt {
+        /// Index files to decrypt. Defaults to every index in the export path.
+        #[clap(parse(from_os_str))]
+        files: Vec<PathBuf>,
+
+        /// An age identity file holding the private key. Without one the configured passphrase is used.
+        #[clap(parse(from_os_str), short, long)]
+        identity: Option<PathBuf>,
+    },
+
+    /// Write a systemd user unit that runs `watch` with the current configuration file, profile and export path.
+    InstallService {
+        /// Print the unit instead of writing it.
+        #[clap(long)]
+        print: bool,
+
+        /// Replace an existing unit.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Inspect, invalidate or verify the cache of exported vault versions.
+    Cache {
+        #[clap(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// Accounts to generate metadata for, as for `sync`. Leave empty to export bookmarks for all accounts.
+    accounts: Vec<String>,
+
+    /// The path to the 1Password 8 database folder to watch. Defaults to the first that exists of ~/Library/Group\ Containers/2BUA8C4S2C.com.1password/Library/Application\ Support/1Password/Data on macOS, or ~/.config/1Password and its Flatpak and Snap equivalents on Linux.
     #[clap(parse(from_os_str), long)]
     watch_path: Option<PathBuf>,
+
+    /// The file name, or glob, of a database whose writes trigger a sync. Writes to its `-journal` and `-wal` files count too. Can be repeated. Defaults to `1password.sqlite`.
+    #[clap(long = "trigger", multiple_occurrences = true)]
+    triggers: Vec<String>,
+
+    /// Check for changed vaults with `op` this often instead of watching the data folder, in seconds or with a unit such as `5m`. The interval grows while nothing changes. Polling every minute is also the fallback when the folder can't be watched.
+    #[clap(long, parse(try_from_str = util::parse_duration))]
+    poll_interval: Option<u64>,
+
+    /// Run a full resync at this local time of day, such as `03:00`, or this often, such as `6h`. It ignores the cache, re-exports every vault and repairs the export folder, logging each missing, modified or stray file it fixes.
+    #[clap(long, parse(try_from_str = resync::Schedule::parse))]
+    resync: Option<resync::Schedule>,
+
+    /// Write the daemon's PID to this file while it runs.
+    #[clap(parse(from_os_str), long)]
+    pid_file: Option<PathBuf>,
+
+    /// The Unix socket to answer `opbookmarks ctl` on. Defaults to a socket named after the export path in the cache folder.
+    #[clap(parse(from_os_str), long)]
+    control_socket: Option<PathBuf>,
+
+    /// Write a JSON line to stdout for every item, vault and account that a sync adds, updates, moves or removes. Everything else the daemon prints goes to stderr.
+    #[clap(long)]
+    events: bool,
+
+    /// Write change events to this FIFO, created when missing. Events are dropped while nothing reads it.
+    #[clap(parse(from_os_str), long)]
+    events_fifo: Option<PathBuf>,
+
+    /// Send change events to every client connected to this Unix socket.
+    #[clap(parse(from_os_str), long)]
+    events_socket: Option<PathBuf>,
 }
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
-struct BookmarkCache {
-    vaults_by_account_id: HashMap<String, Vec<VaultDetails>>,
+impl WatchArgs {
+    fn apply(&self, overrides: &mut config::Overrides) {
+        overrides.accounts.extend(self.accounts.iter().cloned());
+        overrides.watch_path = self.watch_path.clone();
+        overrides.watch_triggers = self.triggers.clone();
+        overrides.poll_interval = self.poll_interval;
+        overrides.resync = self.resync;
+        overrides.pid_file = self.pid_file.clone();
+        overrides.control_socket = self.control_socket.clone();
+        overrides.events_stdout = self.events;
+        overrides.events_fifo = self.events_fifo.clone();
+        overrides.events_socket = self.events_socket.clone();
+    }
 }
 
-impl BookmarkCache {
-    fn vault_content_version(&self, account_id: &String, vault_id: &String) -> usize {
-        let vaults = self.vaults_by_account_id.get(account_id);
+#[derive(Subcommand)]
+enum CtlCommand {
+    /// Export changed vaults now and wait for it to finish, even while paused. Use `--account` to limit it to exported account IDs.
+    Sync {
+        /// Only export vaults matching this selector, as for `--include-vault`.
+        #[clap(long)]
+        vault: Option<String>,
 
-        match vaults {
-            Some(vaults) => {
-                let vault = vaults.iter().find(|v| v.id.eq(vault_id));
-                match vault {
-                    Some(vault) => vault.content_version,
-                    None => 0,
-                }
+        /// Export the vaults even when their versions haven't changed.
+        #[clap(long)]
+        full: bool,
+    },
+
+    /// Show when the daemon last synced, how it went and how many items are exported.
+    Status,
+
+    /// Stop syncing on changes until resumed.
+    Pause,
+
+    /// Sync any changes made while paused and carry on watching.
+    Resume,
+
+    /// Stop the daemon once the current sync is done.
+    Shutdown,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Print the cached accounts and vaults along with their versions.
+    Show,
+
+    /// Forget cached versions so the next sync re-fetches them. Invalidates everything when no account or vault is given.
+    Invalidate {
+        /// Only invalidate vaults of this account ID.
+        #[clap(long)]
+        account: Option<String>,
+
+        /// Only invalidate this vault ID.
+        #[clap(long)]
+        vault: Option<String>,
+    },
+
+    /// Compare the cached versions against those reported by `op vault get`.
+    Verify,
+}
+
+fn main() {
+    let args = Cli::parse();
+
+    // Without a subcommand behave as earlier versions did: sync, then watch if asked to
+    let command = args.command.unwrap_or(if args.watch {
+        Command::Watch(WatchArgs {
+            accounts: args.accounts,
+            watch_path: args.watch_path,
+            triggers: vec![],
+            poll_interval: None,
+            resync: None,
+            pid_file: None,
+            control_socket: None,
+            events: false,
+            events_fifo: None,
+            events_socket: None,
+        })
+    } else {
+        Command::Sync {
+            accounts: args.accounts,
+        }
+    });
+
+    // Purge and ctl work on exported account IDs given explicitly, never on configured selectors
+    let explicit_accounts = args.global.account.clone();
+
+    let mut overrides = config::Overrides {
+        config_path: args.global.config,
+        profile: args.global.profile,
+        accounts: args.global.account,
+        export_path: args.global.export_path,
+        lock_timeout: args.global.lock_timeout,
+        ttl: args.global.ttl,
+        strict_accounts: args.global.strict_accounts,
+        auto_purge: args.global.auto_purge,
+        include_vaults: args.global.include_vault,
+        exclude_vaults: args.global.exclude_vault,
+        include_categories: args.global.include_category,
+        exclude_categories: args.global.exclude_category,
+        include_tags: args.global.include_tag,
+        exclude_tags: args.global.exclude_tag,
+        require_urls: args.global.require_urls,
+        privacy: args.global.privacy,
+        formats: args.global.format,
+        age_recipients: args.global.age_recipient,
+        watch_path: None,
+        watch_triggers: vec![],
+        poll_interval: None,
+        resync: None,
+        pid_file: None,
+        control_socket: None,
+        events_stdout: false,
+        events_fifo: None,
+        events_socket: None,
+        serve_port: None,
+        token_file: None,
+        hooks: args.global.hook,
+    };
+    match &command {
+        Command::Sync { accounts } => overrides.accounts.extend(accounts.iter().cloned()),
+        Command::Watch(watch) => watch.apply(&mut overrides),
+        Command::Serve {
+            watch,
+            port,
+            token_file,
+        } => {
+            watch.apply(&mut overrides);
+            overrides.serve_port = *port;
+            overrides.token_file = token_file.clone();
+        }
+        Command::Ctl { control_socket, .. } => {
+            overrides.control_socket = control_socket.clone();
+        }
+        _ => {}
+    }
+
+    // Kept for the daemon to reload the configuration with
+    let watch_overrides = overrides.clone();
+    let settings = match config::resolve(overrides) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            exit(2);
+        }
+    };
+
+    match command {
+        Command::Sync { .. } => {
+            if !sync(&settings) {
+                exit(1);
+            }
+        }
+        Command::Watch(_) => exit(watch(&watch_overrides, settings, false)),
+        Command::Serve { .. } => exit(watch(&watch_overrides, settings, true)),
+        Command::List { vault } => {
+            bookmarks::list(&load_bookmarks(&settings), vault.as_deref());
+        }
+        Command::Search { query } => {
+            if !bookmarks::search(&load_bookmarks(&settings), &query) {
+                exit(1);
+            }
+        }
+        Command::Open { item, edit } => {
+            if !bookmarks::open(&load_bookmarks(&settings), &item, edit) {
+                exit(1);
             }
-            None => 0,
         }
+        Command::Status => print_status(&settings),
+        Command::Gc { quarantine } => {
+            let action = if quarantine {
+                gc::ExpiryAction::Quarantine
+            } else {
+                settings.on_expiry
+            };
+            if !run_gc(action, &settings) {
+                exit(1);
+            }
+        }
+        Command::Purge { all } => {
+            if !run_purge(&explicit_accounts, all, &settings) {
+                exit(1);
+            }
+        }
+        Command::Decrypt { files, identity } => {
+            if !decrypt(files, identity.as_deref(), &settings) {
+                exit(1);
+            }
+        }
+        Command::Ctl { command, json, .. } => {
+            if !ctl(command, &explicit_accounts, json, &settings) {
+                exit(1);
+            }
+        }
+        Command::InstallService { print, force } => {
+            if !install_service(print, force, &settings) {
+                exit(1);
+            }
+        }
+        Command::Cache { command } => run_cache_command(command, &settings),
     }
 }
 
-fn main() {
+fn sync(settings: &config::Settings) -> bool {
     verify_op_cli_version();
 
-    let args = Cli::parse();
-    if args.accounts.len() == 0 {
+    if let Some(profile) = &settings.profile {
+        println!("Using profile {}", profile);
+    }
+
+    if settings.accounts.is_empty() {
         println!("Will create bookmark metadata for all accounts...");
     } else {
         println!(
-            "Will create bookmark metadata for account user uuids {:?}...",
-            args.accounts
+            "Will create bookmark metadata for accounts {:?}...",
+            settings.accounts
         );
     }
 
-    let export_path = export_path(args.export_path);
-    generate_opbookmarks(&args.accounts, &export_path);
-
-    // Watch for changes
-    if args.watch {
-        let path = match args.watch_path {
-            Some(p) => p,
-            None => {
-                let mut p = dirs::home_dir().unwrap();
-                p.push("Library/Group Containers/2BUA8C4S2C.com.1password/Library/Application Support/1Password/Data");
-                p
+    let options = sync::SyncOptions::default();
+    let result = sync::run(settings, &options);
+    match &result {
+        Ok(summary) => {
+            for (account_id, e) in summary.signed_out.iter() {
+                eprintln!(
+                    "Skipped account {} as op isn't signed in: {}",
+                    account_id,
+                    e.trim_end()
+                );
             }
-        };
-
-        println!("Watching 1Password 8 data folder for changes ({:?})", path);
-        if let Err(e) = watch(path, &args.accounts, &export_path) {
-            println!("error: {:?}", e)
         }
+        Err(e) => eprintln!("Unable to export bookmarks: {}", e),
     }
+    sync::run_hooks(settings, &options, &result);
+    matches!(&result, Ok(summary) if !summary.all_signed_out())
 }
 
 fn verify_op_cli_version() {
@@ -107,177 +538,592 @@ fn verify_op_cli_version() {
     }
 }
 
-fn export_path(cli_path: Option<PathBuf>) -> PathBuf {
-    if let Some(path) = cli_path {
-        return path;
+/// The exported items of the selected accounts for `list`, `search` and `open`
+fn load_bookmarks(settings: &config::Settings) -> Vec<op7_metadata::OP7ItemMetaData> {
+    match bookmarks::load(
+        &settings.export_path,
+        &settings.accounts,
+        settings.strict_accounts,
+    ) {
+        Ok(items) => items,
+        Err(e) => {
+            eprintln!("Unable to select accounts: {}", e);
+            exit(1);
+        }
     }
-
-    let mut path = dirs::home_dir().unwrap();
-    path.push(".config/op/bookmarks");
-    path
 }
 
-fn generate_opbookmarks(account_user_uuids: &Vec<String>, export_path: &std::path::PathBuf) {
-    let cache = load_cache(export_path);
-    let accounts = load_all_accounts(account_user_uuids);
+fn print_status(settings: &config::Settings) {
+    let export_path = &settings.export_path;
+    if let Some(profile) = &settings.profile {
+        println!("Profile: {}", profile);
+    }
+    println!("Export path: {:?}", export_path);
 
-    if let Err(err) = accounts {
-        eprintln!("Failed to load accounts: {:?}", err);
-        exit(1);
+    match lock::state(export_path) {
+        Ok(LockState::Unlocked) => println!("Lock: not held"),
+        Ok(LockState::Held(Some(pid))) => println!("Lock: held by PID {}", pid),
+        Ok(LockState::Held(None)) => println!("Lock: held by an unknown process"),
+        Ok(LockState::Stale { pid, held: false }) => println!(
+            "Lock: stale, PID {} is no longer running and will be replaced on the next sync",
+            pid
+        ),
+        Ok(LockState::Stale { pid, held: true }) => println!(
+            "Lock: held, but its owner PID {} is no longer running. A process it started may still have the lock open.",
+            pid
+        ),
+        Err(e) => println!("Lock: unable to determine state: {}", e),
     }
 
-    let accounts = accounts.unwrap();
-    let mut vaults_by_account: HashMap<AccountDetails, Vec<VaultDetails>> = HashMap::new();
-    let mut items_by_vault: HashMap<VaultDetails, Vec<ItemOverview>> = HashMap::new();
+    let cache = cache::load(export_path);
+    println!(
+        "Cache: {:?} ({} accounts, {} vaults)",
+        cache::cache_path(export_path),
+        cache.vaults_by_account_id.len(),
+        cache
+            .vaults_by_account_id
+            .values()
+            .map(|v| v.len())
+            .sum::<usize>()
+    );
 
+    let manifest = manifest::load(export_path);
+    let mut vaults_by_privacy: std::collections::BTreeMap<String, usize> = Default::default();
+    for vault in manifest
+        .accounts
+        .values()
+        .flat_map(|vaults| vaults.values())
+    {
+        *vaults_by_privacy
+            .entry(vault.privacy.to_string())
+            .or_default() += 1;
+    }
     println!(
-        "Exporting bookmarks for accounts {:?}",
-        accounts
+        "Manifest: {:?} ({})",
+        manifest::manifest_path(export_path),
+        vaults_by_privacy
             .iter()
-            .map(|a| a.id.clone())
+            .map(|(privacy, count)| format!("{} vaults {}", count, privacy))
             .collect::<Vec<String>>()
+            .join(", ")
     );
 
-    // Collect the vaults for each account
-    for account in accounts.iter() {
-        let vaults = load_all_vaults(&account.id);
+    let now = manifest::now();
+    let expired = manifest.expired_vaults(now).len();
+    match manifest.expires_at {
+        _ if expired > 0 => println!(
+            "Expiry: {} vaults have expired, run `opbookmarks sync` or `opbookmarks gc`",
+            expired
+        ),
+        Some(expires_at) => println!(
+            "Expiry: in {}",
+            util::format_duration(expires_at.saturating_sub(now))
+        ),
+        None => println!("Expiry: none, no ttl is configured"),
+    }
 
-        match vaults {
-            Ok(vaults) => {
-                vaults_by_account.insert((*account).clone(), vaults);
-            }
-            Err(err) => {
-                eprintln!(
-                    "Failed to load vaults for account {}: {:?}",
-                    account.id, err
-                );
-            }
-        }
+    let mut items_by_account: HashMap<String, usize> = HashMap::new();
+    for item in op7_metadata::read_items(export_path) {
+        *items_by_account.entry(item.profile_uuid).or_default() += 1;
     }
 
-    // Collect the items for each vault that has changed
-    for (account, vaults) in vaults_by_account.iter() {
-        for vault in vaults.iter() {
-            let export_needed =
-                vault.content_version > cache.vault_content_version(&account.id, &vault.id);
-            if !export_needed {
-                println!("No item changes detected in {}::{}", account.id, vault.id);
-                items_by_vault.insert((*vault).clone(), vec![]);
-                continue;
-            }
+    let mut account_ids: Vec<&String> = items_by_account.keys().collect();
+    account_ids.sort();
+    for account_id in account_ids {
+        println!(
+            "Exported: {} items for account {}",
+            items_by_account[account_id], account_id
+        );
+    }
+}
 
-            let items = op::item_overviews(&account.id, &vault.id);
+fn run_gc(action: gc::ExpiryAction, settings: &config::Settings) -> bool {
+    let export_path = &settings.export_path;
+    let _lock = match lock::acquire(export_path, settings.lock_policy) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Unable to remove expired bookmarks: {}", e);
+            return false;
+        }
+    };
 
-            match items {
-                Ok(items) => {
-                    items_by_vault.insert((*vault).clone(), items);
-                }
-                Err(err) => {
-                    eprintln!(
-                        "Failed to load item overviews for vault {} in account {}: {:?}",
-                        vault.id, account.id, err
-                    )
-                }
-            }
+    let mut cache = cache::load(export_path);
+    let mut manifest = manifest::load(export_path);
+    let result = gc::collect(export_path, &mut cache, &mut manifest, action);
+
+    // Whatever was removed before a failure is still recorded
+    cache::save(&cache);
+    if let Err(e) = manifest::save(export_path, &mut manifest) {
+        eprintln!("Unable to write manifest: {}", e);
+        return false;
+    }
+
+    match result {
+        Ok(0) => {
+            println!("No expired bookmarks");
+            true
+        }
+        Ok(count) => {
+            println!("Removed {} expired vaults", count);
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to remove expired bookmarks: {}", e);
+            false
         }
     }
+}
 
-    // Write out metadata for each item
-    for (account, vaults) in vaults_by_account.iter() {
-        for vault in vaults.iter() {
-            let items = items_by_vault.get(vault);
+fn run_purge(account_ids: &[String], all: bool, settings: &config::Settings) -> bool {
+    let export_path = &settings.export_path;
+    if account_ids.is_empty() && !all {
+        eprintln!("Specify the accounts to purge with --account, or use --all");
+        return false;
+    }
 
-            match items {
-                Some(items) => {
-                    write_items(export_path, items, vault, account);
-                }
-                None => {
-                    eprint!("Unexpected None for items in vault {}", vault.id);
-                }
+    let _lock = match lock::acquire(export_path, settings.lock_policy) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Unable to purge: {}", e);
+            return false;
+        }
+    };
+
+    let mut cache = cache::load(export_path);
+    let mut manifest = manifest::load(export_path);
+    let exported = purge::exported_account_ids(export_path, &cache, &manifest);
+
+    let targets: Vec<String> = if all {
+        exported
+    } else {
+        let mut targets = vec![];
+        for id in account_ids.iter() {
+            match exported.iter().find(|e| e.eq_ignore_ascii_case(id)) {
+                Some(exported_id) => targets.push(exported_id.clone()),
+                None => println!("Nothing exported for account {}", id),
+            }
+        }
+        targets
+    };
+
+    let mut success = true;
+    for account_id in targets.iter() {
+        match purge::purge_account(export_path, &mut cache, &mut manifest, account_id) {
+            Ok(count) => println!("Purged account {}, removed {} files", account_id, count),
+            Err(e) => {
+                eprintln!("Failed to purge account {}: {}", account_id, e);
+                success = false;
             }
         }
     }
-    println!("Metadata files written to {:?}.", export_path);
 
-    let mut vaults_by_account_id: HashMap<String, Vec<VaultDetails>> = HashMap::new();
-    for (account, vault) in vaults_by_account.iter() {
-        vaults_by_account_id.insert(account.clone().id, vault.clone());
+    cache::save(&cache);
+    if let Err(e) = manifest::save(export_path, &mut manifest) {
+        eprintln!("Unable to write manifest: {}", e);
+        success = false;
     }
+    success
+}
 
-    let cache = BookmarkCache {
-        vaults_by_account_id: vaults_by_account_id,
+fn decrypt(
+    files: Vec<PathBuf>,
+    identity: Option<&std::path::Path>,
+    settings: &config::Settings,
+) -> bool {
+    let files = if files.is_empty() {
+        encryption::find_indexes(&settings.export_path)
+    } else {
+        files
     };
-    save_cache(&cache, &export_path);
-}
+    if files.is_empty() {
+        eprintln!("No encrypted indexes found in {:?}", settings.export_path);
+        return false;
+    }
 
-fn load_cache(path: &PathBuf) -> BookmarkCache {
-    let mut path = path.clone();
-    path.push("cache.json");
+    let mut items = vec![];
+    for file in files.iter() {
+        match encryption::read_index(file, identity, settings.encryption.as_ref()) {
+            Ok(index) => items.extend(index),
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        }
+    }
 
-    let json = std::fs::read_to_string(path);
+    println!("{}", serde_json::to_string_pretty(&items).unwrap());
+    true
+}
 
-    match json {
-        Ok(json) => {
-            let cache: Result<BookmarkCache, serde_json::Error> =
-                serde_json::from_str(json.as_str());
+fn run_cache_command(command: CacheCommand, settings: &config::Settings) {
+    let export_path = &settings.export_path;
 
-            match cache {
-                Ok(cache) => cache,
+    match command {
+        CacheCommand::Show => cache::show(&cache::load(export_path)),
+        CacheCommand::Invalidate { account, vault } => {
+            // Don't pull the cache out from under a sync in progress
+            let _lock = match lock::acquire(export_path, settings.lock_policy) {
+                Ok(lock) => lock,
                 Err(e) => {
-                    eprint!(
-                        "Reseting caches because cache.json could not be deserialized: {:?}",
-                        e
-                    );
-                    BookmarkCache::default()
+                    eprintln!("Unable to invalidate cache: {}", e);
+                    exit(1);
                 }
+            };
+
+            let mut cache = cache::load(export_path);
+            let count = cache.invalidate(account.as_deref(), vault.as_deref());
+            cache::save(&cache);
+            println!(
+                "Invalidated {} cached vaults. They will be re-fetched on the next sync.",
+                count
+            );
+        }
+        CacheCommand::Verify => {
+            verify_op_cli_version();
+
+            if !cache::verify(&cache::load(export_path), &settings.vault_filters) {
+                exit(1);
             }
         }
-        Err(_) => BookmarkCache::default(),
     }
 }
 
-fn save_cache(cache: &BookmarkCache, path: &PathBuf) {
-    let mut path = path.clone();
-    path.push("cache.json");
-    match serde_json::to_string(&cache) {
-        Ok(json) => {
-            util::write_file(path, json);
-        }
-        Err(err) => {
-            eprint!("Error serializing json for cache: {}", err);
+fn install_service(print: bool, force: bool, settings: &config::Settings) -> bool {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Unable to find the opbookmarks executable: {}", e);
+            return false;
         }
     };
+    let unit = systemd::unit(settings, &exe);
+    if print {
+        print!("{}", unit);
+        return true;
+    }
+
+    let name = systemd::unit_name(settings);
+    let path = systemd::unit_path(&name);
+    if path.exists() && !force {
+        eprintln!("{:?} already exists. Use --force to replace it.", path);
+        return false;
+    }
+
+    // ReadWritePaths that don't exist stop the service from starting
+    for folder in [
+        &settings.export_path,
+        &cache::cache_dir(),
+        &privacy::data_dir(),
+    ] {
+        if let Err(e) = std::fs::create_dir_all(folder) {
+            eprintln!("Unable to create {:?}: {}", folder, e);
+            return false;
+        }
+    }
+    if let Err(e) = util::write_file(path.clone(), unit) {
+        eprintln!("Unable to write {:?}: {}", path, e);
+        return false;
+    }
+
+    println!("Wrote {:?}. To start it now and on every login run:", path);
+    println!("  systemctl --user daemon-reload");
+    println!("  systemctl --user enable --now {}", name);
+    true
+}
+
+/// The data folder to watch, or none when polling
+fn watch_target(settings: &config::Settings) -> Result<Option<PathBuf>, platform::Error> {
+    match (&settings.watch_path, settings.poll_interval) {
+        (_, Some(_)) => Ok(None),
+        (Some(path), None) => Ok(Some(path.clone())),
+        (None, None) => platform::default_data_dir().map(Some),
+    }
 }
 
-fn watch(
-    path: std::path::PathBuf,
-    account_user_uuids: &Vec<String>,
-    export_path: &std::path::PathBuf,
-) -> notify::Result<()> {
-    use notify::DebouncedEvent;
-    let (tx, rx) = channel();
+/// Syncs whenever the 1Password data folder changes, or when polling finds a changed vault if
+/// there's no folder to watch, until a signal asks the daemon to stop. Falls back to polling when
+/// the watcher fails. With a ttl the export is also refreshed halfway through it so it doesn't
+/// expire while nothing changes. With `serve` the exported items are also served over HTTP.
+/// Returns the exit code.
+fn watch(overrides: &config::Overrides, mut settings: config::Settings, serve: bool) -> i32 {
+    let mut path = match watch_target(&settings) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
 
-    let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(5))?;
+    let _pid_file = match &settings.pid_file {
+        Some(pid_file) => match daemon::PidFile::create(pid_file) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+    if let Err(e) = daemon::handle_signals(settings.pid_file.clone()) {
+        eprintln!("Unable to handle signals: {}", e);
+        return 1;
+    }
 
-    watcher.watch(path, RecursiveMode::Recursive)?;
+    let mut daemon = match daemon::Daemon::start(&settings) {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    // Filled in by the first sync, which exports every vault to do so
+    let model = if serve {
+        Some(serve::Model::default())
+    } else {
+        None
+    };
+    let _server = match &model {
+        Some(model) => {
+            match serve::Server::start(settings.serve_port, &settings.token_file, model.clone()) {
+                Ok(server) => {
+                    println!(
+                        "Serving bookmarks on http://127.0.0.1:{}/ with the token in {:?}",
+                        settings.serve_port, settings.token_file
+                    );
+                    Some(server)
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            }
+        }
+        None => None,
+    };
+    let files = heal::Files::default();
+    let sync_defaults = sync::SyncOptions {
+        model,
+        files: Some(files.clone()),
+        events: daemon.wants_events(),
+        ..Default::default()
+    };
+
+    verify_op_cli_version();
+    if let Some(profile) = &settings.profile {
+        println!("Using profile {}", profile);
+    }
+
+    // Clear out whatever went stale while the daemon wasn't running
+    if !run_gc(settings.on_expiry, &settings) {
+        return 1;
+    }
+    let report = daemon.sync(&settings, &sync_defaults, "Starting up");
+    if daemon.sign_in.is_signed_out() {
+        systemd::ready("Waiting for op to be signed in");
+    } else if let Some(error) = &report.error {
+        // Counts towards giving up like any other failed sync rather than exiting straight away
+        systemd::ready(&format!(
+            "Last sync failed ({} in a row): {}",
+            daemon.failures, error
+        ));
+    } else {
+        systemd::ready("Last sync succeeded");
+    }
 
     loop {
-        match rx.recv() {
-            Ok(event) => match event {
-                DebouncedEvent::NoticeRemove(path) => {
-                    // SQLite removes the journal file after merging the contents with 1password.sqlite
-                    if path.ends_with("1password.sqlite-journal") {
-                        println!("1Password 8 data file changed. Updating metadata files...");
-                        generate_opbookmarks(account_user_uuids, export_path);
-                    } else {
-                        println!("Ignoring NoticeRemove of {:?}", path);
+        let mut triggers = triggers::Triggers::new(&settings, path.as_deref(), files.clone());
+
+        // Runs until the configuration is reloaded
+        loop {
+            daemon.update(|status| {
+                status.mode = triggers.mode().to_string();
+                status.export_path = settings.export_path.clone();
+                status.next_resync_at = triggers.next_resync();
+            });
+
+            systemd::watchdog();
+            if daemon::shutdown_requested() {
+                systemd::stopping();
+                println!("Stopped watching for changes");
+                return 0;
+            }
+            if daemon.failures >= daemon::MAX_CONSECUTIVE_FAILURES {
+                eprintln!("Giving up after {} failures in a row", daemon.failures);
+                return 1;
+            }
+            if daemon::take_reload() {
+                systemd::reloading();
+                match reload(overrides) {
+                    Ok((reloaded, reloaded_path)) => {
+                        settings = reloaded;
+                        path = reloaded_path;
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("Keeping the current configuration: {}", e);
+                        systemd::ready(&format!("Keeping the current configuration: {}", e));
+                        continue;
                     }
                 }
-                _ => {
-                    println!("Ignoring event {:?}", event)
-                }
-            },
-            Err(e) => println!("watch error: {:?}", e),
+            }
+
+            // Asked for over the control socket, so run even while paused
+            if let Some(request) = daemon.next_request() {
+                let options = sync::SyncOptions {
+                    full: request.full,
+                    accounts: request.accounts.clone(),
+                    vault: request
+                        .vault
+                        .as_deref()
+                        .and_then(|v| filter::VaultSelector::parse(v).ok()),
+                    ..sync_defaults.clone()
+                };
+                let report = daemon.sync(&settings, &options, "Sync requested");
+                request.reply(report);
+                triggers.synced();
+                continue;
+            }
+
+            if let Some(trigger) = triggers.next(&settings, &mut daemon) {
+                let options = sync::SyncOptions {
+                    full: trigger.full,
+                    ..sync_defaults.clone()
+                };
+                daemon.sync(&settings, &options, &trigger.reason);
+                triggers.synced();
+            }
         }
+
+        daemon.sync(&settings, &sync_defaults, "Configuration reloaded");
+        systemd::ready("Configuration reloaded");
     }
 }
+
+/// Sends a request to the watch daemon and prints its response. Returns false when the request,
+/// or the sync it asked for, failed.
+fn ctl(
+    command: CtlCommand,
+    account_ids: &[String],
+    json: bool,
+    settings: &config::Settings,
+) -> bool {
+    let request = match command {
+        CtlCommand::Sync { vault, full } => control::Request::Sync {
+            accounts: account_ids.to_vec(),
+            vault,
+            full,
+        },
+        CtlCommand::Status => control::Request::Status,
+        CtlCommand::Pause => control::Request::Pause,
+        CtlCommand::Resume => control::Request::Resume,
+        CtlCommand::Shutdown => control::Request::Shutdown,
+    };
+
+    let response = match control::send(&settings.control_socket, &request) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&response) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Unable to print response: {}", e),
+        }
+        return response.ok;
+    }
+
+    if let Some(error) = &response.error {
+        eprintln!("Error: {}", error);
+    }
+    if let Some(status) = &response.status {
+        let now = manifest::now();
+        println!(
+            "Daemon PID {} {} {:?}, running for {}{}",
+            status.pid,
+            status.mode,
+            status.export_path,
+            util::format_duration(now.saturating_sub(status.started_at)),
+            if status.paused { ", paused" } else { "" }
+        );
+        if status.syncing {
+            println!("Syncing now");
+        }
+        match &status.last_sync {
+            Some(report) => print_sync_report(report, now),
+            None => println!("No sync yet"),
+        }
+        if status.failures > 0 {
+            println!("{} failures in a row", status.failures);
+        }
+        if let Some(signed_out) = &status.signed_out {
+            println!(
+                "Waiting for op to be signed in{}: {}",
+                match status.signed_out_since {
+                    Some(since) =>
+                        format!(" for {}", util::format_duration(now.saturating_sub(since))),
+                    None => String::new(),
+                },
+                signed_out.trim_end()
+            );
+        }
+        if !status.signed_out_accounts.is_empty() {
+            println!(
+                "Skipping accounts op isn't signed in to: {}",
+                status.signed_out_accounts.join(", ")
+            );
+        }
+        if let Some(at) = status.next_resync_at {
+            println!(
+                "Next full resync in {}",
+                util::format_duration(at.saturating_sub(now))
+            );
+        }
+        if status.repaired.total() > 0 {
+            println!("Repaired since starting: {}", status.repaired);
+        }
+        for (account_id, vaults) in status.items.iter() {
+            for (vault_id, items) in vaults.iter() {
+                println!("  {}::{} {} items", account_id, vault_id, items);
+            }
+        }
+    }
+    if let Some(report) = &response.sync {
+        print_sync_report(report, manifest::now());
+    }
+    if response.ok && response.status.is_none() && response.sync.is_none() {
+        println!("Done");
+    }
+
+    response.ok
+}
+
+fn print_sync_report(report: &control::SyncReport, now: u64) {
+    let outcome = match &report.error {
+        Some(error) => format!("failed: {}", error),
+        None => format!("exported {} vaults", report.exported),
+    };
+    println!(
+        "Last sync {} ago ({}), took {}ms, {}",
+        util::format_duration(now.saturating_sub(report.started_at)),
+        report.reason,
+        report.duration_ms,
+        outcome
+    );
+    for error in report.errors.iter() {
+        println!("  {}", error);
+    }
+    if let Some(drift) = &report.drift {
+        println!("  Repaired {}", drift);
+    }
+}
+
+/// Resolves the configuration again for SIGHUP, along with the folder to watch
+fn reload(overrides: &config::Overrides) -> Result<(config::Settings, Option<PathBuf>), String> {
+    let settings = config::resolve(overrides.clone()).map_err(|e| e.to_string())?;
+    let path = watch_target(&settings).map_err(|e| e.to_string())?;
+    Ok((settings, path))
+}
@@ -0,0 +1,213 @@
+/// Describes what was exported, and how, in manifest.json at the root of the export path
+use crate::encryption::ExportFormat;
+use crate::privacy::PrivacyLevel;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+pub const MANIFEST_VERSION: u64 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u64,
+    /// The opbookmarks release that wrote the manifest
+    pub generator: String,
+    /// When a sync last completed, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub synced_at: Option<u64>,
+    /// The time-to-live in seconds that sync was configured with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// The earliest expiry of any vault. Past this time at least part of the export is stale.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Vaults by vault ID, by account ID. Vault names are left out as they may be what a privacy
+    /// level is hiding.
+    #[serde(default)]
+    pub accounts: BTreeMap<String, BTreeMap<String, VaultManifest>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VaultManifest {
+    pub privacy: PrivacyLevel,
+    /// Manifests written before encrypted exports only had JSON files
+    #[serde(default = "default_formats")]
+    pub formats: Vec<ExportFormat>,
+    /// The fingerprint of the item filter the vault was exported with. Missing from manifests
+    /// written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item_filter: Option<String>,
+    /// The fingerprint of the key titles were hashed with, for vaults exported at the `hashed`
+    /// privacy level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_key: Option<String>,
+    pub items: usize,
+    /// Seconds since the Unix epoch
+    pub exported_at: u64,
+    /// When a sync last confirmed the files are current, even if nothing had to be written
+    #[serde(default)]
+    pub synced_at: u64,
+    /// After this the files shouldn't be trusted and `opbookmarks gc` removes them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The hex SHA-256 of every file written for the vault, by file name within the account's
+    /// folder. Missing from manifests written before checksums were recorded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checksums: BTreeMap<String, String>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            generator: generator(),
+            synced_at: None,
+            ttl: None,
+            expires_at: None,
+            accounts: BTreeMap::new(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn vault(&self, account_id: &str, vault_id: &str) -> Option<&VaultManifest> {
+        self.accounts.get(account_id)?.get(vault_id)
+    }
+
+    pub fn vault_mut(&mut self, account_id: &str, vault_id: &str) -> Option<&mut VaultManifest> {
+        self.accounts.get_mut(account_id)?.get_mut(vault_id)
+    }
+
+    pub fn remove_vault(&mut self, account_id: &str, vault_id: &str) {
+        if let Some(vaults) = self.accounts.get_mut(account_id) {
+            vaults.remove(vault_id);
+        }
+    }
+
+    /// (account ID, vault ID) of every vault that expired by `now`
+    pub fn expired_vaults(&self, now: u64) -> Vec<(String, String)> {
+        let mut expired = vec![];
+        for (account_id, vaults) in self.accounts.iter() {
+            for (vault_id, vault) in vaults.iter() {
+                if vault.is_expired(now) {
+                    expired.push((account_id.clone(), vault_id.clone()));
+                }
+            }
+        }
+        expired
+    }
+}
+
+impl VaultManifest {
+    pub fn new(
+        privacy: PrivacyLevel,
+        formats: &[ExportFormat],
+        item_filter: String,
+        title_key: Option<String>,
+        items: usize,
+        ttl: Option<u64>,
+        files: &BTreeMap<String, Vec<u8>>,
+    ) -> VaultManifest {
+        let now = now();
+        VaultManifest {
+            privacy,
+            formats: formats.to_vec(),
+            item_filter: Some(item_filter),
+            title_key,
+            items,
+            exported_at: now,
+            synced_at: now,
+            expires_at: ttl.and_then(|ttl| now.checked_add(ttl)),
+            checksums: files
+                .iter()
+                .map(|(name, contents)| (name.clone(), checksum(contents)))
+                .collect(),
+        }
+    }
+
+    /// Records that a sync found the exported files to be current
+    pub fn touch(&mut self, ttl: Option<u64>) {
+        self.synced_at = now();
+        self.expires_at = ttl.and_then(|ttl| self.synced_at.checked_add(ttl));
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+pub fn manifest_path(export_path: &Path) -> PathBuf {
+    let mut path = export_path.to_path_buf();
+    path.push(MANIFEST_FILE_NAME);
+    path
+}
+
+/// Loads the manifest, starting afresh when it's missing or unreadable. Vaults without an entry
+/// are re-exported so the manifest never claims a privacy level their files weren't written with.
+pub fn load(export_path: &Path) -> Manifest {
+    let path = manifest_path(export_path);
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Manifest::default(),
+        Err(e) => {
+            eprintln!("Unable to read manifest {:?}: {}", path, e);
+            return Manifest::default();
+        }
+    };
+
+    match serde_json::from_str::<Manifest>(&json) {
+        Ok(manifest) if manifest.version <= MANIFEST_VERSION => manifest,
+        Ok(manifest) => {
+            eprintln!(
+                "Ignoring manifest {:?} written by newer opbookmarks ({}).",
+                path, manifest.generator
+            );
+            Manifest::default()
+        }
+        Err(e) => {
+            eprintln!("Ignoring unreadable manifest {:?}: {}", path, e);
+            Manifest::default()
+        }
+    }
+}
+
+pub fn save(export_path: &Path, manifest: &mut Manifest) -> std::io::Result<()> {
+    manifest.version = MANIFEST_VERSION;
+    manifest.generator = generator();
+    manifest.expires_at = manifest
+        .accounts
+        .values()
+        .flat_map(|vaults| vaults.values())
+        .filter_map(|v| v.expires_at)
+        .min();
+
+    let json = serde_json::to_string_pretty(manifest)?;
+    crate::util::write_file(manifest_path(export_path), json)
+}
+
+fn default_formats() -> Vec<ExportFormat> {
+    vec![ExportFormat::Json]
+}
+
+fn generator() -> String {
+    format!("opbookmarks {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// The hex SHA-256 of a file's contents
+pub fn checksum(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
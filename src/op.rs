@@ -1,4 +1,5 @@
 /// Uses the `op` CLI to load Account, Vault, and Item information
+use crate::filter::{select_accounts, AccountCandidate};
 use serde::{Deserialize, Serialize};
 use std::process::{Command, Stdio};
 
@@ -7,6 +8,9 @@ pub struct AccountOverview {
     pub email: String,
     pub url: String,
     pub user_uuid: String,
+    /// Missing from older `op` releases
+    #[serde(default)]
+    pub account_uuid: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
@@ -41,7 +45,7 @@ pub struct VaultDetails {
     pub updated_at: String,
 }
 
-#[derive(Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct ItemOverview {
     pub id: String,
     pub vault: VaultOverview,
@@ -84,19 +88,59 @@ pub struct ItemDetails {
     pub urls: Option<Vec<OPURL>>,
 }
 
-#[derive(Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct OPURL {
     pub primary: Option<bool>,
     pub href: String,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum Error {
     OPCLI(String),
+    /// `op` isn't signed in, its session expired or the 1Password app is locked
+    Auth(String),
     Deserialize(serde_json::Error),
+    AccountSelection(String),
     // Serialize(serde_json::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::OPCLI(e) => write!(f, "op error: {}", e.trim_end()),
+            Error::Auth(e) => write!(f, "op isn't signed in: {}", e.trim_end()),
+            Error::Deserialize(e) => write!(f, "unable to parse op output: {}", e),
+            Error::AccountSelection(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Lowercase parts of what `op` prints when it can't be used until someone signs in or unlocks
+/// 1Password, as opposed to failing for some other reason
+const AUTH_ERRORS: [&str; 9] = [
+    "not currently signed in",
+    "account is not signed in",
+    "session expired",
+    "invalid session token",
+    "authorization prompt dismissed",
+    "authorization timeout",
+    "authentication required",
+    "app is locked",
+    "connecting to desktop app",
+];
+
+fn cli_error(stderr: &[u8]) -> Error {
+    let message = String::from_utf8_lossy(stderr).to_string();
+    let lowercase = message.to_lowercase();
+    if AUTH_ERRORS.iter().any(|e| lowercase.contains(e)) {
+        Error::Auth(message)
+    } else {
+        Error::OPCLI(message)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum OPStatus {
     NotInstalled,
@@ -120,31 +164,54 @@ pub fn status() -> OPStatus {
     }
 }
 
-pub fn load_all_accounts(account_user_uuids: &Vec<String>) -> Result<Vec<AccountDetails>, Error> {
-    let accounts = find_accounts(account_user_uuids);
-
-    match accounts {
-        Ok(accounts) => {
-            let mut details: Vec<AccountDetails> = vec![];
-            for account in accounts.iter() {
-                let ad = get_account(&account.user_uuid);
-
-                match ad {
-                    Ok(ad) => details.push(ad),
-                    Err(e) => {
-                        eprint!("Error loading account details: {:?}", e);
-                        return Err(Error::OPCLI(format!(
-                            "Failed to load details for account {}",
-                            account.user_uuid
-                        )));
-                    }
-                }
-            }
+/// Loads the accounts picked by the selectors, or every account when there are none. See
+/// `filter::select_accounts` for what a selector can match.
+pub fn load_all_accounts(selectors: &[String], strict: bool) -> Result<Vec<AccountDetails>, Error> {
+    let accounts = find_accounts()?;
+
+    if selectors.is_empty() {
+        println!(
+            "Including all found accounts for export: {}",
+            accounts.len()
+        );
+    }
 
-            Ok(details)
+    let mut candidates: Vec<AccountCandidate> = accounts
+        .into_iter()
+        .map(|overview| AccountCandidate {
+            overview,
+            details: None,
+        })
+        .collect();
+
+    let selected: Vec<usize> = if selectors.is_empty() {
+        (0..candidates.len()).collect()
+    } else {
+        select_accounts(selectors, &mut candidates, load_account_details, strict)?
+    };
+
+    let mut details: Vec<AccountDetails> = vec![];
+    for i in selected {
+        match candidates[i].details.take() {
+            Some(ad) => details.push(ad),
+            None => details.push(load_account_details(&candidates[i].overview)?),
         }
-        Err(e) => Err(e),
     }
+
+    Ok(details)
+}
+
+fn load_account_details(account: &AccountOverview) -> Result<AccountDetails, Error> {
+    get_account(&account.user_uuid).map_err(|e| match e {
+        Error::Auth(_) => e,
+        e => {
+            eprintln!("Error loading account details: {:?}", e);
+            Error::OPCLI(format!(
+                "Failed to load details for account {}",
+                account.user_uuid
+            ))
+        }
+    })
 }
 
 pub fn version() -> Option<String> {
@@ -155,7 +222,7 @@ pub fn version() -> Option<String> {
             let mut version = output.stdout;
             let error = output.stderr;
 
-            if error.len() > 0 {
+            if !error.is_empty() {
                 println!(
                     "Error running `op --version`: {}",
                     String::from_utf8_lossy(&error)
@@ -170,7 +237,7 @@ pub fn version() -> Option<String> {
     }
 }
 
-pub fn find_accounts(account_user_uuids: &Vec<String>) -> Result<Vec<AccountOverview>, Error> {
+pub fn find_accounts() -> Result<Vec<AccountOverview>, Error> {
     let output = Command::new("op")
         .arg("--cache")
         .arg("--format")
@@ -182,44 +249,11 @@ pub fn find_accounts(account_user_uuids: &Vec<String>) -> Result<Vec<AccountOver
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
-    let accounts: Result<Vec<AccountOverview>, Error> =
-        serde_json::from_slice(json.as_slice()).map_err(|e| Error::Deserialize(e));
-
-    match accounts {
-        Ok(accounts) => {
-            if account_user_uuids.len() == 0 {
-                println!(
-                    "Including all found accounts for export: {}",
-                    accounts.len()
-                );
-                Ok(accounts)
-            } else {
-                // Limit to the specified accounts
-                let mut specified_accounts: Vec<AccountOverview> = vec![];
-                for uuid in account_user_uuids.iter() {
-                    match accounts.iter().find(|a| (*a).user_uuid == uuid.as_str()) {
-                        Some(account) => {
-                            specified_accounts.push(account.clone());
-                        }
-                        None => {
-                            eprintln!(
-                                "Cannot include specified account {} for export as it couldn't be found",
-                                uuid
-                            );
-                        }
-                    }
-                }
-                Ok(specified_accounts)
-            }
-        }
-        Err(e) => Err(e),
-    }
+    serde_json::from_slice(json.as_slice()).map_err(Error::Deserialize)
 }
 
 // op --account BXRGOJ2Z5JB4RMA7FUYUURELUE --format json account get
@@ -237,13 +271,11 @@ pub fn get_account(user_id: &String) -> Result<AccountDetails, Error> {
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
-    serde_json::from_slice(json.as_slice()).map_err(|e| Error::Deserialize(e))
+    serde_json::from_slice(json.as_slice()).map_err(Error::Deserialize)
 }
 
 // op --format json --account A vault list | op --format json --account A vault get --format json -
@@ -261,10 +293,8 @@ pub fn load_all_vaults(account_id: &String) -> Result<Vec<VaultDetails>, Error>
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
     let mut vault_details_cmd = Command::new("op")
@@ -297,10 +327,8 @@ pub fn load_all_vaults(account_id: &String) -> Result<Vec<VaultDetails>, Error>
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
     let mut de = serde_json::Deserializer::from_slice(&json);
@@ -318,7 +346,6 @@ pub fn load_all_vaults(account_id: &String) -> Result<Vec<VaultDetails>, Error>
     Ok(vaults)
 }
 
-#[allow(dead_code)]
 pub fn find_vaults(account_id: &String) -> Result<Vec<VaultOverview>, Error> {
     let output = Command::new("op")
         .arg("--cache")
@@ -333,17 +360,14 @@ pub fn find_vaults(account_id: &String) -> Result<Vec<VaultOverview>, Error> {
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
-    serde_json::from_slice(json.as_slice()).map_err(|e| Error::Deserialize(e))
+    serde_json::from_slice(json.as_slice()).map_err(Error::Deserialize)
 }
 
 // op --account BXRGOJ2Z5JB4RMA7FUYUURELUE --format json vault get jnnjfdrzr5rawkimmsvp3zzzxe
-#[allow(dead_code)]
 pub fn get_vault(account_id: &String, vault_id: &String) -> Result<VaultDetails, Error> {
     let output = Command::new("op")
         .arg("--cache")
@@ -359,13 +383,11 @@ pub fn get_vault(account_id: &String, vault_id: &String) -> Result<VaultDetails,
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
-    serde_json::from_slice(json.as_slice()).map_err(|e| Error::Deserialize(e))
+    serde_json::from_slice(json.as_slice()).map_err(Error::Deserialize)
 }
 
 // op --format json --account A --vault V item list | op --format json --account A --vault V item get --format json -
@@ -386,10 +408,8 @@ pub fn load_all_items(account_id: &String, vault_id: &String) -> Result<Vec<Item
     let json = list_output.stdout;
     let error = list_output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
     let mut item_details_cmd = Command::new("op")
@@ -424,10 +444,8 @@ pub fn load_all_items(account_id: &String, vault_id: &String) -> Result<Vec<Item
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
     let mut de = serde_json::Deserializer::from_slice(&json);
@@ -462,13 +480,11 @@ pub fn item_overviews(account_id: &String, vault_id: &String) -> Result<Vec<Item
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
-    serde_json::from_slice(json.as_slice()).map_err(|e| Error::Deserialize(e))
+    serde_json::from_slice(json.as_slice()).map_err(Error::Deserialize)
 }
 
 // op --account BXRGOJ2Z5JB4RMA7FUYUURELUE --vault jnnjfdrzr5rawkimmsvp3zzzxe --format json item get fu5rgmahfihx4j6lludeyx3oei
@@ -494,11 +510,9 @@ pub fn get_item(
     let json = output.stdout;
     let error = output.stderr;
 
-    if error.len() > 0 {
-        return Err(Error::OPCLI(
-            std::str::from_utf8(error.as_slice()).unwrap().to_string(),
-        ));
+    if !error.is_empty() {
+        return Err(cli_error(&error));
     }
 
-    serde_json::from_slice(json.as_slice()).map_err(|e| Error::Deserialize(e))
+    serde_json::from_slice(json.as_slice()).map_err(Error::Deserialize)
 }
@@ -1,99 +1,318 @@
 /// Create metadata files that conform to the format used by 1Password 7
 use crate::op::{AccountDetails, ItemOverview, VaultDetails};
+use crate::privacy::PrivacyLevel;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct OP7ItemMetaData {
-    uuid: String,
+    pub uuid: String,
 
     #[serde(rename = "profileUUID")]
-    profile_uuid: String,
+    pub profile_uuid: String,
 
     #[serde(rename = "vaultUUID")]
-    vault_uuid: String,
+    pub vault_uuid: String,
 
     #[serde(rename = "categoryUUID")]
-    category_uuid: String,
+    pub category_uuid: String,
 
     #[serde(rename = "itemTitle")]
-    item_title: String,
+    pub item_title: String,
 
     #[serde(rename = "itemDescription")]
-    item_description: String,
+    pub item_description: String,
 
     #[serde(rename = "websiteURLs")]
-    website_urls: Vec<String>,
+    pub website_urls: Vec<String>,
 
     #[serde(rename = "accountName")]
-    account_name: String,
+    pub account_name: String,
 
     #[serde(rename = "vaultName")]
-    vault_name: String,
+    pub vault_name: String,
 
     #[serde(rename = "categoryPluralName")]
-    category_plural_name: String,
+    pub category_plural_name: String,
 
     #[serde(rename = "categorySingularName")]
-    category_singular_name: String,
+    pub category_singular_name: String,
 
     #[serde(rename = "modifiedAt")]
-    modified_at: usize,
+    pub modified_at: usize,
 
     #[serde(rename = "createdAt")]
-    created_at: usize,
+    pub created_at: usize,
+
+    /// Only written by the `ids-only` privacy level, which leaves nothing else to open the item by
+    #[serde(rename = "deepLink", default, skip_serializing_if = "Option::is_none")]
+    pub deep_link: Option<String>,
 }
 
-pub fn write_items(
-    export_path: &std::path::PathBuf,
-    items: &Vec<ItemOverview>,
+impl OP7ItemMetaData {
+    /// The URL that shows the item in 1Password
+    pub fn view_url(&self) -> String {
+        format!(
+            "onepassword://view-item/?a={}&v={}&i={}",
+            self.profile_uuid, self.vault_uuid, self.uuid
+        )
+    }
+
+    /// The URL that opens the item for editing in 1Password
+    pub fn edit_url(&self) -> String {
+        format!(
+            "onepassword://edit-item/?a={}&v={}&i={}",
+            self.profile_uuid, self.vault_uuid, self.uuid
+        )
+    }
+}
+
+pub const METADATA_EXTENSION: &str = "onepassword-item-metadata";
+
+/// Converts items to the 1Password 7 format, redacted for the privacy level
+pub fn create_items(
+    items: &[ItemOverview],
     vault: &VaultDetails,
     account: &AccountDetails,
-) {
-    let mut path = export_path.clone();
-    path.push(account.id.clone());
+    privacy: PrivacyLevel,
+) -> std::io::Result<Vec<OP7ItemMetaData>> {
+    let title_key = match privacy {
+        PrivacyLevel::Hashed => crate::privacy::title_key()?,
+        _ => vec![],
+    };
+
+    Ok(items
+        .iter()
+        .map(|item| {
+            let mut op7_item = create_op7_metadata(item, vault, &account.id);
+            crate::privacy::redact(&mut op7_item, privacy, &title_key);
+            op7_item
+        })
+        .collect())
+}
 
+/// Writes a JSON file for each item. Returns what was written by file name within the account's
+/// folder.
+pub fn write_items(
+    export_path: &std::path::Path,
+    items: &[OP7ItemMetaData],
+) -> std::io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut written = BTreeMap::new();
     for item in items.iter() {
-        let op7_item = create_op7_metadata(&item, &vault, &account.id);
-
-        match serde_json::to_string(&op7_item) {
-            Ok(json) => {
-                let mut path = path.clone();
-                path.push(format!(
-                    "{}_{}.onepassword-item-metadata",
-                    vault.id, item.id
-                ));
-                crate::util::write_file(path, json);
+        let json = serde_json::to_string(item)?;
+
+        let name = format!("{}_{}.{}", item.vault_uuid, item.uuid, METADATA_EXTENSION);
+        let mut path = export_path.to_path_buf();
+        path.push(&item.profile_uuid);
+        path.push(&name);
+        crate::util::write_file(path, json.clone())?;
+        written.insert(name, json.into_bytes());
+    }
+
+    Ok(written)
+}
+
+/// Deletes the metadata files written for a vault. Returns how many were removed.
+pub fn remove_vault(
+    export_path: &std::path::Path,
+    account_id: &str,
+    vault_id: &str,
+) -> std::io::Result<usize> {
+    remove_items_except(export_path, account_id, vault_id, &[])
+}
+
+/// Deletes the metadata files of a vault's items that aren't in `keep`, such as deleted or
+/// filtered items. Returns how many were removed.
+pub fn remove_items_except(
+    export_path: &std::path::Path,
+    account_id: &str,
+    vault_id: &str,
+    keep: &[&str],
+) -> std::io::Result<usize> {
+    let mut path = export_path.to_path_buf();
+    path.push(account_id);
+
+    let files = match std::fs::read_dir(&path) {
+        Ok(files) => files,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let prefix = format!("{}_", vault_id);
+    let suffix = format!(".{}", METADATA_EXTENSION);
+    let mut count = 0;
+    for file in files {
+        let path = file?.path();
+        let item_id = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|n| n.strip_suffix(&suffix));
+
+        if let Some(item_id) = item_id {
+            if !keep.contains(&item_id) {
+                std::fs::remove_file(&path)?;
+                count += 1;
             }
-            Err(err) => {
-                eprint!(
-                    "Error serializing item json for vault {}: {}",
-                    vault.id, err
-                );
+        }
+    }
+
+    Ok(count)
+}
+
+/// The metadata files written for a vault
+pub fn vault_files(
+    export_path: &std::path::Path,
+    account_id: &str,
+    vault_id: &str,
+) -> std::io::Result<Vec<std::path::PathBuf>> {
+    let mut path = export_path.to_path_buf();
+    path.push(account_id);
+
+    let files = match std::fs::read_dir(&path) {
+        Ok(files) => files,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let prefix = format!("{}_", vault_id);
+    let suffix = format!(".{}", METADATA_EXTENSION);
+    let mut paths = vec![];
+    for file in files {
+        let path = file?.path();
+        let is_vault_file = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with(&prefix) && n.ends_with(&suffix))
+            .unwrap_or(false);
+        if is_vault_file {
+            paths.push(path);
+        }
+    }
+
+    Ok(paths)
+}
+
+/// The IDs of the vaults with metadata files in an account's folder
+pub fn exported_vault_ids(
+    export_path: &std::path::Path,
+    account_id: &str,
+) -> std::io::Result<Vec<String>> {
+    let mut path = export_path.to_path_buf();
+    path.push(account_id);
+
+    let files = match std::fs::read_dir(&path) {
+        Ok(files) => files,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let suffix = format!(".{}", METADATA_EXTENSION);
+    let mut vault_ids = vec![];
+    for file in files {
+        let name = file?.file_name();
+        let vault_id = name
+            .to_str()
+            .and_then(|n| n.strip_suffix(&suffix))
+            .and_then(|n| n.split_once('_'))
+            .map(|(vault_id, _)| vault_id.to_string());
+        if let Some(vault_id) = vault_id {
+            vault_ids.push(vault_id);
+        }
+    }
+
+    vault_ids.sort();
+    vault_ids.dedup();
+    Ok(vault_ids)
+}
+
+/// Loads the metadata files previously written for a vault. Unreadable files are skipped.
+pub fn read_vault_items(
+    export_path: &std::path::Path,
+    account_id: &str,
+    vault_id: &str,
+) -> Vec<OP7ItemMetaData> {
+    let paths = match vault_files(export_path, account_id, vault_id) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!(
+                "Unable to read exported files of {}::{}: {}",
+                account_id, vault_id, e
+            );
+            return vec![];
+        }
+    };
+
+    paths
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .filter_map(|json| serde_json::from_str(&json).ok())
+        .collect()
+}
+
+/// Loads every metadata file previously written to the export path
+pub fn read_items(export_path: &std::path::Path) -> Vec<OP7ItemMetaData> {
+    let mut items = vec![];
+
+    let accounts = match std::fs::read_dir(export_path) {
+        Ok(accounts) => accounts,
+        Err(_) => return items,
+    };
+
+    for account in accounts.flatten() {
+        if !account.path().is_dir() {
+            continue;
+        }
+
+        let files = match std::fs::read_dir(account.path()) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Unable to read {:?}: {}", account.path(), e);
+                continue;
             }
         };
+
+        for file in files.flatten() {
+            let path = file.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(METADATA_EXTENSION) {
+                continue;
+            }
+
+            let item = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+
+            match item {
+                Ok(item) => items.push(item),
+                Err(e) => eprintln!("Skipping unreadable metadata file {:?}: {}", path, e),
+            }
+        }
     }
+
+    items
 }
 
 fn create_op7_metadata(
     item: &ItemOverview,
     vault: &VaultDetails,
-    account_id: &String,
+    account_id: &str,
 ) -> OP7ItemMetaData {
     let website_urls = item.urls_as_vec();
 
-    return OP7ItemMetaData {
+    OP7ItemMetaData {
         uuid: item.id.clone(),
         item_description: format!("Login from {}", &vault.name.clone()),
         item_title: item.title.clone(),
         vault_name: vault.name.clone(),
         vault_uuid: vault.id.clone(),
         category_plural_name: item.category.clone(), // TODO: Map SECURE_NOTE, etc
-        profile_uuid: account_id.clone(),
-        website_urls: website_urls,
+        profile_uuid: account_id.to_string(),
+        website_urls,
         category_singular_name: item.category.clone(),
         category_uuid: "001".to_string(),
         account_name: "".to_string(), // TODO: Not sure anyone uses this?
         modified_at: 0,               // TODO: parse item.modified_at
         created_at: 0,                // TODO: parse item.created_at,
-    };
+        deep_link: None,
+    }
 }
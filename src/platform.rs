@@ -0,0 +1,66 @@
+/// Finds the 1Password 8 data folder for the current platform
+use std::path::PathBuf;
+
+/// The database whose writes, along with those of its journal and WAL files, trigger a sync
+pub const DEFAULT_TRIGGERS: &[&str] = &["1password.sqlite"];
+
+#[derive(Debug)]
+pub enum Error {
+    /// None of the probed folders exist
+    NotFound(Vec<PathBuf>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotFound(candidates) => {
+                write!(f, "unable to find the 1Password 8 data folder, looked in:")?;
+                for candidate in candidates.iter() {
+                    write!(f, "\n  {}", candidate.display())?;
+                }
+                write!(
+                    f,
+                    "\nUse --watch-path or `path` in the profile's [watch] table to set it."
+                )
+            }
+        }
+    }
+}
+
+/// Where 1Password keeps its database on this platform, most likely first
+pub fn data_dir_candidates() -> Vec<PathBuf> {
+    let home = dirs::home_dir().unwrap();
+    let in_home = |path: &str| {
+        let mut p = home.clone();
+        p.push(path);
+        p
+    };
+
+    if cfg!(target_os = "macos") {
+        return vec![in_home(
+            "Library/Group Containers/2BUA8C4S2C.com.1password/Library/Application Support/1Password/Data",
+        )];
+    }
+
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => in_home(".config"),
+    };
+
+    vec![
+        config_dir.join("1Password"),
+        // Flatpak
+        in_home(".var/app/com.onepassword.OnePassword/config/1Password"),
+        // Snap
+        in_home("snap/1password/current/.config/1Password"),
+    ]
+}
+
+/// The first candidate data folder that exists
+pub fn default_data_dir() -> Result<PathBuf, Error> {
+    let candidates = data_dir_candidates();
+    match candidates.iter().find(|c| c.is_dir()) {
+        Some(dir) => Ok(dir.clone()),
+        None => Err(Error::NotFound(candidates)),
+    }
+}
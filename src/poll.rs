@@ -0,0 +1,115 @@
+/// Checks vault versions with `op` for changes, for when the data folder can't be watched
+use crate::cache::BookmarkCache;
+use crate::config::Settings;
+use crate::op::{load_all_accounts, load_all_vaults, Error};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Used when the watcher fails and no interval was configured
+pub const DEFAULT_INTERVAL: u64 = 60;
+/// The interval grows up to this many times its configured value while nothing changes
+const MAX_BACKOFF: u32 = 10;
+
+/// Polls less often the longer nothing changes, and returns to the configured interval as soon
+/// as something does.
+pub struct PollInterval {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl PollInterval {
+    pub fn new(seconds: u64) -> PollInterval {
+        let base = Duration::from_secs(seconds.max(1));
+        PollInterval {
+            base,
+            max: base * MAX_BACKOFF,
+            current: base,
+        }
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    pub fn changed(&mut self) {
+        self.current = self.base;
+    }
+
+    pub fn unchanged(&mut self) {
+        self.current = std::cmp::min(self.current * 3 / 2, self.max);
+    }
+}
+
+/// The changes earlier polls reported: the `content_version` and `attribute_version` of changed
+/// vaults, or `None` for removed ones, by `<account ID>::<vault ID>`. A vault whose export failed
+/// is still changed as far as the cache goes, and it isn't worth syncing again until it changes
+/// once more.
+#[derive(Default)]
+pub struct Reported(HashMap<String, Option<(usize, usize)>>);
+
+/// Describes the exported vaults whose `content_version` or `attribute_version` moved past the
+/// cached one, or that appeared or disappeared, leaving out changes an earlier poll reported. Only
+/// vaults are loaded, never items.
+pub fn changed_vaults(
+    settings: &Settings,
+    cache: &BookmarkCache,
+    reported: &mut Reported,
+) -> Result<Vec<String>, Error> {
+    let accounts = load_all_accounts(&settings.accounts, settings.strict_accounts)?;
+    let mut changes = HashMap::new();
+    let mut signed_out = None;
+    let mut checked = 0;
+
+    for account in accounts.iter() {
+        // Syncs skip suspended accounts, so their vaults would be changed forever
+        if crate::purge::is_suspended(account) {
+            continue;
+        }
+        let vaults = match load_all_vaults(&account.id) {
+            Ok(vaults) => vaults,
+            // Syncs report accounts that aren't signed in, so the rest are still checked
+            Err(Error::Auth(e)) => {
+                signed_out = Some(e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        checked += 1;
+        let vaults: Vec<_> = vaults
+            .into_iter()
+            .filter(|v| crate::filter::is_vault_exported(&settings.vault_filters, account, v))
+            .collect();
+
+        for vault in vaults.iter() {
+            if cache.has_changed(&account.id, vault) {
+                changes.insert(
+                    format!("{}::{}", account.id, vault.id),
+                    Some((vault.content_version, vault.attribute_version)),
+                );
+            }
+        }
+
+        if let Some(cached) = cache.vaults_by_account_id.get(&account.id) {
+            for removed in cached
+                .iter()
+                .filter(|c| !vaults.iter().any(|v| v.id == c.id))
+            {
+                changes.insert(format!("{}::{}", account.id, removed.id), None);
+            }
+        }
+    }
+
+    if let Some(e) = signed_out.filter(|_| checked == 0) {
+        return Err(Error::Auth(e));
+    }
+
+    let mut changed: Vec<String> = changes
+        .iter()
+        .filter(|(vault, change)| reported.0.get(*vault) != Some(change))
+        .map(|(vault, _)| vault.clone())
+        .collect();
+    changed.sort();
+    reported.0 = changes;
+    Ok(changed)
+}
@@ -0,0 +1,315 @@
+/// Redacts exported metadata for vaults that shouldn't reveal everything to disk readers
+use crate::filter::VaultSelector;
+use crate::op::{AccountDetails, ItemOverview, VaultDetails, OPURL};
+use crate::op7_metadata::OP7ItemMetaData;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::io::Read;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrivacyLevel {
+    /// Everything 1Password 7 exported
+    #[default]
+    Full,
+    /// Website URLs are reduced to their registrable domain and the description is omitted
+    Domains,
+    /// As `Domains`, with the title replaced by a keyed hash that only supports exact lookups
+    Hashed,
+    /// Only the account, vault and item IDs along with a deep link to the item
+    IdsOnly,
+}
+
+impl std::str::FromStr for PrivacyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(PrivacyLevel::Full),
+            "domains" => Ok(PrivacyLevel::Domains),
+            "hashed" => Ok(PrivacyLevel::Hashed),
+            "ids-only" => Ok(PrivacyLevel::IdsOnly),
+            _ => Err(format!(
+                "unknown privacy level {:?}, expected full, domains, hashed or ids-only",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for PrivacyLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PrivacyLevel::Full => "full",
+            PrivacyLevel::Domains => "domains",
+            PrivacyLevel::Hashed => "hashed",
+            PrivacyLevel::IdsOnly => "ids-only",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A `[[profiles.<name>.privacy_rules]]` entry from the config file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivacyRuleConfig {
+    /// Limits the rule to the account with this ID or name
+    pub account: Option<String>,
+    /// Vault selectors, as used by vault filters
+    pub vaults: Vec<String>,
+    pub level: PrivacyLevel,
+}
+
+#[derive(Clone, Debug)]
+pub struct PrivacyRule {
+    account: Option<String>,
+    vaults: Vec<VaultSelector>,
+    level: PrivacyLevel,
+}
+
+impl PrivacyRule {
+    pub fn parse(config: &PrivacyRuleConfig) -> Result<PrivacyRule, String> {
+        Ok(PrivacyRule {
+            account: config.account.clone(),
+            vaults: config
+                .vaults
+                .iter()
+                .map(|s| VaultSelector::parse(s))
+                .collect::<Result<Vec<VaultSelector>, String>>()?,
+            level: config.level,
+        })
+    }
+
+    fn matches(&self, account: &AccountDetails, vault: &VaultDetails) -> bool {
+        let account_matches = match &self.account {
+            Some(selector) => {
+                account.id.eq_ignore_ascii_case(selector)
+                    || account.name.eq_ignore_ascii_case(selector)
+            }
+            None => true,
+        };
+
+        account_matches && self.vaults.iter().any(|s| s.matches(vault))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PrivacySettings {
+    pub default_level: PrivacyLevel,
+    pub rules: Vec<PrivacyRule>,
+}
+
+impl PrivacySettings {
+    /// The level of the first matching rule, or the default
+    pub fn level_for(&self, account: &AccountDetails, vault: &VaultDetails) -> PrivacyLevel {
+        self.rules
+            .iter()
+            .find(|r| r.matches(account, vault))
+            .map(|r| r.level)
+            .unwrap_or(self.default_level)
+    }
+}
+
+pub fn redact(item: &mut OP7ItemMetaData, level: PrivacyLevel, title_key: &[u8]) {
+    match level {
+        PrivacyLevel::Full => {}
+        PrivacyLevel::Domains => {
+            item.item_description = "".to_string();
+            item.website_urls = domains_only(&item.website_urls);
+        }
+        PrivacyLevel::Hashed => {
+            item.item_description = "".to_string();
+            item.item_title = hash_title(&item.item_title, title_key);
+            item.website_urls = domains_only(&item.website_urls);
+        }
+        PrivacyLevel::IdsOnly => {
+            item.deep_link = Some(item.view_url());
+            item.item_description = "".to_string();
+            item.item_title = "".to_string();
+            item.vault_name = "".to_string();
+            item.website_urls = vec![];
+            item.category_plural_name = "".to_string();
+            item.category_singular_name = "".to_string();
+        }
+    }
+}
+
+/// Redacts an item served by `opbookmarks serve` as its exported file would be
+pub fn redact_overview(item: &mut ItemOverview, level: PrivacyLevel, title_key: &[u8]) {
+    match level {
+        PrivacyLevel::Full => {}
+        PrivacyLevel::Domains => {
+            item.additional_info = None;
+            item.urls = domains_only_urls(&item.urls);
+        }
+        PrivacyLevel::Hashed => {
+            item.additional_info = None;
+            item.title = hash_title(&item.title, title_key);
+            item.urls = domains_only_urls(&item.urls);
+        }
+        PrivacyLevel::IdsOnly => {
+            item.additional_info = None;
+            item.title = "".to_string();
+            item.vault.name = None;
+            item.urls = vec![];
+            item.tags = vec![];
+            item.category = "".to_string();
+            item.last_edited_by = "".to_string();
+        }
+    }
+}
+
+const HASHED_TITLE_PREFIX: &str = "hmac-sha256:";
+
+pub fn is_hashed_title(title: &str) -> bool {
+    title.starts_with(HASHED_TITLE_PREFIX)
+}
+
+/// Hashes a title the same way as `Hashed` exports so it can be looked up. Titles are lower cased
+/// first so lookups ignore case.
+pub fn hash_title(title: &str, key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(title.to_lowercase().as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", HASHED_TITLE_PREFIX, hex)
+}
+
+const TITLE_KEY_FILE_NAME: &str = "title-hash.key";
+
+/// The folder kept for the title hash key: `$XDG_DATA_HOME/opbookmarks`, falling back to
+/// `~/.local/share/opbookmarks`. Unlike the cache folder it isn't safe to delete, as a new key
+/// changes every hashed title.
+pub fn data_dir() -> PathBuf {
+    let mut path = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut p = dirs::home_dir().unwrap();
+            p.push(".local/share");
+            p
+        }
+    };
+    path.push("opbookmarks");
+    path
+}
+
+pub fn title_key_path() -> PathBuf {
+    let mut path = data_dir();
+    path.push(TITLE_KEY_FILE_NAME);
+    path
+}
+
+/// Where the key was kept before it moved to the data folder
+fn legacy_title_key_path() -> PathBuf {
+    let mut path = crate::cache::cache_dir();
+    path.push(TITLE_KEY_FILE_NAME);
+    path
+}
+
+/// The key for `hash_title`, or `None` when there isn't one yet
+pub fn existing_title_key() -> std::io::Result<Option<Vec<u8>>> {
+    for path in [title_key_path(), legacy_title_key_path()] {
+        match std::fs::read(&path) {
+            Ok(key) if !key.is_empty() => return Ok(Some(key)),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// The key for `hash_title`, created on first use and only readable by the current user. A key
+/// left in the cache folder by older versions is moved to the data folder.
+pub fn title_key() -> std::io::Result<Vec<u8>> {
+    let path = title_key_path();
+    let key = match existing_title_key()? {
+        Some(key) => key,
+        None => {
+            let mut key = vec![0u8; 32];
+            std::fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+            key
+        }
+    };
+
+    if !path.exists() {
+        crate::util::write_private_file(&path, &key)?;
+        let _ = std::fs::remove_file(legacy_title_key_path());
+    }
+    Ok(key)
+}
+
+/// Identifies the key `Hashed` exports are written with in the manifest, so vaults hashed with
+/// another key are exported again. `None` for other levels, or when there's no usable key.
+pub fn title_key_fingerprint(privacy: PrivacyLevel) -> Option<String> {
+    if privacy != PrivacyLevel::Hashed {
+        return None;
+    }
+    let mut fingerprint = crate::manifest::checksum(&title_key().ok()?);
+    fingerprint.truncate(16);
+    Some(fingerprint)
+}
+
+fn domains_only(urls: &[String]) -> Vec<String> {
+    let mut domains: Vec<String> = vec![];
+    for url in urls.iter() {
+        if let Some(domain) = registrable_domain(url) {
+            let domain = format!("https://{}", domain);
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    domains
+}
+
+fn domains_only_urls(urls: &[OPURL]) -> Vec<OPURL> {
+    let hrefs: Vec<String> = urls.iter().map(|u| u.href.clone()).collect();
+    domains_only(&hrefs)
+        .into_iter()
+        .map(|href| OPURL {
+            primary: None,
+            href,
+        })
+        .collect()
+}
+
+/// Second level labels that are public suffixes under common country code domains. A full
+/// public suffix list is overkill for hiding paths and subdomains.
+const SECOND_LEVEL_SUFFIXES: &[&str] = &[
+    "ac", "co", "com", "edu", "gov", "govt", "ltd", "ne", "net", "nhs", "or", "org", "plc", "sch",
+];
+
+/// `https://www.shop.example.co.uk/cart` becomes `example.co.uk`
+pub fn registrable_domain(url: &str) -> Option<String> {
+    let without_scheme = match url.split_once("://") {
+        Some((_, rest)) => rest,
+        None => url,
+    };
+    let authority = without_scheme.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?.trim_end_matches('.').to_lowercase();
+
+    if host.is_empty() {
+        return None;
+    }
+
+    let labels: Vec<&str> = host.split('.').collect();
+    let is_ip = labels.iter().all(|l| l.parse::<u8>().is_ok());
+    if labels.len() <= 2 || is_ip {
+        return Some(host);
+    }
+
+    let tld = labels[labels.len() - 1];
+    let second = labels[labels.len() - 2];
+    let keep = if tld.len() == 2 && SECOND_LEVEL_SUFFIXES.contains(&second) {
+        3
+    } else {
+        2
+    };
+
+    Some(labels[labels.len() - keep..].join("."))
+}
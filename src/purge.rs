@@ -0,0 +1,96 @@
+/// Removes everything exported for accounts that are no longer wanted or no longer available
+use crate::cache::BookmarkCache;
+use crate::manifest::Manifest;
+use crate::op::{AccountDetails, AccountOverview};
+use crate::op7_metadata::{OP7ItemMetaData, METADATA_EXTENSION};
+use std::path::Path;
+
+const SUSPENDED_STATE: &str = "SUSPENDED";
+
+/// Account IDs with anything in the cache or manifest, or with a folder in the export path
+/// holding metadata that was exported for that account. Other folders are left alone as they may
+/// belong to the user or another tool.
+pub fn exported_account_ids(
+    export_path: &Path,
+    cache: &BookmarkCache,
+    manifest: &Manifest,
+) -> Vec<String> {
+    let mut ids: Vec<String> = cache.vaults_by_account_id.keys().cloned().collect();
+    ids.extend(manifest.accounts.keys().cloned());
+
+    if let Ok(entries) = std::fs::read_dir(export_path) {
+        for entry in entries.flatten() {
+            let account_id = entry.file_name().to_string_lossy().into_owned();
+            if !ids.contains(&account_id) && holds_metadata_of(&entry.path(), &account_id) {
+                ids.push(account_id);
+            }
+        }
+    }
+
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// Whether the folder has a metadata file whose `profile_uuid` is the account ID
+fn holds_metadata_of(folder: &Path, account_id: &str) -> bool {
+    let files = match std::fs::read_dir(folder) {
+        Ok(files) => files,
+        Err(_) => return false,
+    };
+
+    files.flatten().any(|file| {
+        let path = file.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(METADATA_EXTENSION) {
+            return false;
+        }
+        let item: Option<OP7ItemMetaData> = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok());
+        matches!(item, Some(item) if item.profile_uuid == account_id)
+    })
+}
+
+/// Deletes the account's folder of exported files and forgets it in the cache and manifest.
+/// Returns how many files were removed.
+pub fn purge_account(
+    export_path: &Path,
+    cache: &mut BookmarkCache,
+    manifest: &mut Manifest,
+    account_id: &str,
+) -> std::io::Result<usize> {
+    let mut path = export_path.to_path_buf();
+    path.push(account_id);
+
+    let count = match std::fs::read_dir(&path) {
+        Ok(files) => files.count(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if count > 0 || path.exists() {
+        std::fs::remove_dir_all(&path)?;
+    }
+
+    cache.vaults_by_account_id.remove(account_id);
+    cache.item_versions.remove(account_id);
+    manifest.accounts.remove(account_id);
+    Ok(count)
+}
+
+/// Exported accounts missing from `op account list`. Nothing is reported when the list is empty
+/// or lacks account IDs, as that says more about `op` than about the accounts.
+pub fn vanished_accounts(exported: &[String], listed: &[AccountOverview]) -> Vec<String> {
+    if listed.is_empty() || listed.iter().any(|a| a.account_uuid.is_empty()) {
+        return vec![];
+    }
+
+    exported
+        .iter()
+        .filter(|id| !listed.iter().any(|a| a.account_uuid == **id))
+        .cloned()
+        .collect()
+}
+
+pub fn is_suspended(account: &AccountDetails) -> bool {
+    account.state.eq_ignore_ascii_case(SUSPENDED_STATE)
+}
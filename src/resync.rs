@@ -0,0 +1,478 @@
+/// Scheduled full resyncs, which heal drift between the export folder and what was exported
+///
+/// Syncs only look at vaults whose versions moved, so a file deleted or edited by hand, or a
+/// change the daemon missed, stays wrong until the vault changes again. A full sync re-exports
+/// every vault regardless of the cache and counts what it had to repair on the way.
+use crate::cache::BookmarkCache;
+use crate::encryption::ExportFormat;
+use crate::manifest::Manifest;
+use crate::op::{AccountDetails, VaultDetails};
+use crate::op7_metadata::{self, OP7ItemMetaData};
+use crate::sync::{ExportedVault, Observer, SyncSummary};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// When the daemon runs a full resync
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Schedule {
+    /// Seconds since the last one
+    Every(u64),
+    /// Every day at this local hour and minute
+    Daily(u32, u32),
+}
+
+impl Schedule {
+    /// Parses a time of day such as `"03:30"` or an interval such as `"6h"`
+    pub fn parse(s: &str) -> Result<Schedule, String> {
+        if let Some((hour, minute)) = s.trim().split_once(':') {
+            return match (hour.parse::<u32>(), minute.parse::<u32>()) {
+                (Ok(hour), Ok(minute)) if hour < 24 && minute < 60 => {
+                    Ok(Schedule::Daily(hour, minute))
+                }
+                _ => Err(format!("{:?} isn't a time of day such as \"03:30\"", s)),
+            };
+        }
+        match crate::util::parse_duration(s)? {
+            0 => Err("the interval can't be zero".to_string()),
+            seconds => Ok(Schedule::Every(seconds)),
+        }
+    }
+
+    /// The Unix time of the first resync after `now`
+    pub fn next(&self, now: u64) -> u64 {
+        match *self {
+            Schedule::Every(seconds) => now.saturating_add(seconds),
+            Schedule::Daily(hour, minute) => next_daily(&Local, hour, minute, now),
+        }
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Every(seconds) => {
+                write!(f, "every {}", crate::util::format_duration(*seconds))
+            }
+            Schedule::Daily(hour, minute) => write!(f, "daily at {:02}:{:02}", hour, minute),
+        }
+    }
+}
+
+/// Converts between Unix time and a time zone's calendar time
+trait TimeZone {
+    fn calendar(&self, time: u64) -> Option<libc::tm>;
+
+    /// Fields out of range, such as a day past the end of the month, carry over as with `mktime`
+    fn unix(&self, tm: &mut libc::tm) -> Option<u64>;
+}
+
+/// The system's time zone
+struct Local;
+
+impl TimeZone for Local {
+    fn calendar(&self, time: u64) -> Option<libc::tm> {
+        let time = time as libc::time_t;
+        unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            match libc::localtime_r(&time, &mut tm).is_null() {
+                true => None,
+                false => Some(tm),
+            }
+        }
+    }
+
+    fn unix(&self, tm: &mut libc::tm) -> Option<u64> {
+        match unsafe { libc::mktime(tm) } {
+            -1 => None,
+            t => Some(t as u64),
+        }
+    }
+}
+
+/// Works in calendar time, so a daily resync keeps its time of day across daylight saving
+fn next_daily(zone: &impl TimeZone, hour: u32, minute: u32, now: u64) -> u64 {
+    let at = |days: i32| {
+        let mut tm = zone.calendar(now)?;
+        tm.tm_mday += days;
+        tm.tm_hour = hour as i32;
+        tm.tm_min = minute as i32;
+        tm.tm_sec = 0;
+        tm.tm_isdst = -1;
+        zone.unix(&mut tm)
+    };
+
+    match at(0) {
+        Some(today) if today > now => today,
+        _ => at(1).unwrap_or_else(|| now.saturating_add(DAY)),
+    }
+}
+
+/// What a full sync had to repair
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Drift {
+    /// Files of unchanged items that were missing and written again
+    pub missing: usize,
+    /// Files of unchanged items whose contents had been changed, and were rewritten
+    pub modified: usize,
+    /// Files no exported item accounts for, such as those of vaults deleted in 1Password, that
+    /// were removed
+    pub stray: usize,
+    /// Item changes that syncs missed as their vault's version hadn't moved
+    pub missed: usize,
+}
+
+impl Drift {
+    pub fn total(&self) -> usize {
+        self.missing + self.modified + self.stray + self.missed
+    }
+
+    pub fn add(&mut self, other: &Drift) {
+        self.missing += other.missing;
+        self.modified += other.modified;
+        self.stray += other.stray;
+        self.missed += other.missed;
+    }
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} missing, {} modified and {} stray files, {} missed item changes",
+            self.missing, self.modified, self.stray, self.missed
+        )
+    }
+}
+
+/// Follows a full sync of every account and vault, counting what it repairs and finding the files
+/// of vaults deleted in 1Password
+pub struct Reconciler {
+    /// The cache the sync started with
+    previous: BookmarkCache,
+    /// Files are only compared when they're written as JSON
+    json: bool,
+    /// The files of the vault being exported, when they should come out the same
+    files: Option<BTreeMap<String, String>>,
+    /// Vaults found deleted in 1Password, by account and vault ID
+    vanished: BTreeSet<(String, String)>,
+    drift: Drift,
+}
+
+impl Reconciler {
+    pub fn new(cache: &BookmarkCache, formats: &[ExportFormat]) -> Reconciler {
+        Reconciler {
+            previous: cache.clone(),
+            json: formats.contains(&ExportFormat::Json),
+            files: None,
+            vanished: BTreeSet::new(),
+            drift: Drift::default(),
+        }
+    }
+}
+
+impl Observer for Reconciler {
+    fn exporting(
+        &mut self,
+        export_path: &Path,
+        account_id: &str,
+        vault_id: &str,
+        same_settings: bool,
+    ) {
+        // Files rewritten for new settings were expected to change, so they aren't drift
+        self.files = match same_settings && self.json {
+            true => Some(read_files(export_path, account_id, vault_id)),
+            false => None,
+        };
+    }
+
+    fn exported(
+        &mut self,
+        account: &AccountDetails,
+        vault: &VaultDetails,
+        exported: &ExportedVault,
+    ) -> Result<(), String> {
+        let (no_files, no_versions) = (BTreeMap::new(), BTreeMap::new());
+        let files = self.files.take();
+        let (files, items) = match &files {
+            Some(files) => (files, &exported.items[..]),
+            None => (&no_files, &[][..]),
+        };
+        compare(
+            &mut self.drift,
+            &format!("{}::{}", account.id, vault.id),
+            files,
+            items,
+            self.previous
+                .item_versions
+                .get(&account.id)
+                .and_then(|v| v.get(&vault.id))
+                .unwrap_or(&no_versions),
+            &exported.versions,
+            self.previous.has_changed(&account.id, vault),
+        );
+        Ok(())
+    }
+
+    /// Anything exported for a vault the account no longer lists: files, encrypted indexes, and
+    /// entries in the cache or manifest
+    fn vanished_vaults(
+        &mut self,
+        export_path: &Path,
+        account_id: &str,
+        vaults: &[VaultDetails],
+        cache: &BookmarkCache,
+        manifest: &Manifest,
+    ) -> Vec<String> {
+        let mut vanished =
+            op7_metadata::exported_vault_ids(export_path, account_id).unwrap_or_default();
+        vanished.extend(
+            crate::encryption::indexed_vault_ids(export_path, account_id).unwrap_or_default(),
+        );
+        vanished.extend(
+            cache
+                .item_versions
+                .get(account_id)
+                .into_iter()
+                .flat_map(|v| v.keys().cloned()),
+        );
+        vanished.extend(
+            manifest
+                .accounts
+                .get(account_id)
+                .into_iter()
+                .flat_map(|v| v.keys().cloned()),
+        );
+        vanished.sort();
+        vanished.dedup();
+        vanished.retain(|id| !vaults.iter().any(|v| v.id == *id));
+
+        for vault_id in vanished.iter() {
+            self.vanished
+                .insert((account_id.to_string(), vault_id.clone()));
+        }
+        vanished
+    }
+
+    fn vault_removed(&mut self, account_id: &str, vault_id: &str, count: usize) {
+        if self
+            .vanished
+            .contains(&(account_id.to_string(), vault_id.to_string()))
+        {
+            self.drift.stray += count;
+        }
+    }
+
+    fn finished(
+        &mut self,
+        summary: &mut SyncSummary,
+        _previous: &BookmarkCache,
+        _cache: &BookmarkCache,
+    ) {
+        match self.drift.total() {
+            0 => println!("Full sync found the export folder as it should be"),
+            _ => println!(
+                "Full sync repaired drift in the export folder: {}",
+                self.drift
+            ),
+        }
+        summary.drift = Some(std::mem::take(&mut self.drift));
+    }
+}
+
+/// The contents of a vault's metadata files by item ID, read before they're rewritten
+pub fn read_files(
+    export_path: &Path,
+    account_id: &str,
+    vault_id: &str,
+) -> BTreeMap<String, String> {
+    op7_metadata::vault_files(export_path, account_id, vault_id)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_str()?;
+            let item_id = name.strip_prefix(vault_id)?.strip_prefix('_')?;
+            let json = std::fs::read_to_string(path).ok()?;
+            Some((item_id.to_string(), json))
+        })
+        .collect()
+}
+
+/// Compares the metadata files of a vault from before it was re-exported with what was written,
+/// and the item versions in the cache with those just fetched, logging each repair. `files` and
+/// `items` are empty when the vault isn't exported as JSON.
+pub fn compare(
+    drift: &mut Drift,
+    vault: &str,
+    files: &BTreeMap<String, String>,
+    items: &[OP7ItemMetaData],
+    previous: &BTreeMap<String, usize>,
+    current: &BTreeMap<String, usize>,
+    vault_changed: bool,
+) {
+    for item in items.iter() {
+        let unchanged =
+            matches!(previous.get(&item.uuid), Some(v) if Some(v) == current.get(&item.uuid));
+        if !unchanged {
+            continue;
+        }
+        match files.get(&item.uuid) {
+            None => {
+                println!("Restored missing file of item {} in {}", item.uuid, vault);
+                drift.missing += 1;
+            }
+            Some(json) if serde_json::to_string(item).ok().as_ref() != Some(json) => {
+                println!("Rewrote modified file of item {} in {}", item.uuid, vault);
+                drift.modified += 1;
+            }
+            Some(_) => {}
+        }
+    }
+
+    for item_id in files.keys() {
+        if !previous.contains_key(item_id) && !current.contains_key(item_id) {
+            println!("Removed stray file of item {} in {}", item_id, vault);
+            drift.stray += 1;
+        }
+    }
+
+    if !vault_changed {
+        let missed = previous
+            .keys()
+            .chain(current.keys())
+            .filter(|id| previous.get(*id) != current.get(*id))
+            .collect::<std::collections::BTreeSet<_>>()
+            .len();
+        if missed > 0 {
+            println!(
+                "Found {} item changes in {} that were missed as its version hadn't changed",
+                missed, vault
+            );
+            drift.missed += missed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::item;
+
+    /// The UK in 2024: GMT, and BST (UTC+1) from 01:00 UTC on 31 March to 01:00 UTC on 27 October
+    struct Uk2024;
+
+    impl Uk2024 {
+        fn offset(time: u64) -> u64 {
+            match time {
+                1711846800..=1729990799 => 60 * 60,
+                _ => 0,
+            }
+        }
+    }
+
+    impl TimeZone for Uk2024 {
+        fn calendar(&self, time: u64) -> Option<libc::tm> {
+            let time = (time + Uk2024::offset(time)) as libc::time_t;
+            unsafe {
+                let mut tm: libc::tm = std::mem::zeroed();
+                match libc::gmtime_r(&time, &mut tm).is_null() {
+                    true => None,
+                    false => Some(tm),
+                }
+            }
+        }
+
+        fn unix(&self, tm: &mut libc::tm) -> Option<u64> {
+            let local = unsafe { libc::timegm(tm) } as u64;
+            // Good enough away from the hour the clocks skip or repeat
+            Some(local - Uk2024::offset(local - Uk2024::offset(local)))
+        }
+    }
+
+    fn versions(items: &[(&str, usize)]) -> BTreeMap<String, usize> {
+        items.iter().map(|(id, v)| (id.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn schedules_parse_times_of_day_and_intervals() {
+        assert_eq!(Schedule::parse("03:30"), Ok(Schedule::Daily(3, 30)));
+        assert_eq!(Schedule::parse(" 0:05 "), Ok(Schedule::Daily(0, 5)));
+        assert_eq!(Schedule::parse("6h"), Ok(Schedule::Every(6 * 60 * 60)));
+        assert!(Schedule::parse("24:00").is_err());
+        assert!(Schedule::parse("12:60").is_err());
+        assert!(Schedule::parse("3:3x").is_err());
+        assert!(Schedule::parse("0").is_err());
+        assert!(Schedule::parse("99999999999999w").is_err());
+    }
+
+    #[test]
+    fn daily_resyncs_keep_their_local_time_across_daylight_saving() {
+        // 2024-03-30 01:00 GMT: later the same day
+        assert_eq!(next_daily(&Uk2024, 3, 30, 1711760400), 1711769400);
+        // 2024-03-30 03:30 GMT exactly: the next day, which is in BST
+        assert_eq!(next_daily(&Uk2024, 3, 30, 1711769400), 1711852200);
+        // 2024-03-30 12:00 GMT: 03:30 BST the next day, 23 hours later
+        assert_eq!(next_daily(&Uk2024, 3, 30, 1711800000), 1711852200);
+        // 2024-10-26 12:00 UTC (13:00 BST): 03:30 GMT the next day, 25 hours after 02:30 UTC
+        assert_eq!(next_daily(&Uk2024, 3, 30, 1729944000), 1729999800);
+    }
+
+    #[test]
+    fn compare_counts_missing_and_modified_files_of_unchanged_items() {
+        let items = vec![
+            item("A", "v1", "i1", "GitHub"),
+            item("A", "v1", "i2", "Visa"),
+            item("A", "v1", "i3", "Wifi"),
+        ];
+        let files = BTreeMap::from([
+            ("i2".to_string(), "edited by hand".to_string()),
+            ("i3".to_string(), serde_json::to_string(&items[2]).unwrap()),
+        ]);
+        let current = versions(&[("i1", 1), ("i2", 1), ("i3", 1)]);
+
+        let mut drift = Drift::default();
+        compare(
+            &mut drift, "A::v1", &files, &items, &current, &current, true,
+        );
+        assert_eq!((drift.missing, drift.modified, drift.stray), (1, 1, 0));
+        assert_eq!(drift.missed, 0);
+    }
+
+    #[test]
+    fn compare_leaves_changed_items_to_the_export() {
+        let items = vec![item("A", "v1", "i1", "GitHub")];
+        let previous = versions(&[("i1", 1)]);
+        let current = versions(&[("i1", 2)]);
+
+        let mut drift = Drift::default();
+        compare(
+            &mut drift,
+            "A::v1",
+            &BTreeMap::new(),
+            &items,
+            &previous,
+            &current,
+            true,
+        );
+        assert_eq!(drift.total(), 0);
+    }
+
+    #[test]
+    fn compare_counts_stray_files_and_missed_changes() {
+        let files = BTreeMap::from([("gone".to_string(), "{}".to_string())]);
+        let previous = versions(&[("i1", 1), ("i2", 1)]);
+        let current = versions(&[("i1", 2), ("i3", 1)]);
+
+        let mut drift = Drift::default();
+        compare(&mut drift, "A::v1", &files, &[], &previous, &current, false);
+        assert_eq!(drift.stray, 1);
+        // i1 changed, i2 was removed and i3 added without the vault's version moving
+        assert_eq!(drift.missed, 3);
+
+        let mut drift = Drift::default();
+        compare(&mut drift, "A::v1", &files, &[], &previous, &current, true);
+        assert_eq!(drift.missed, 0);
+    }
+}
@@ -0,0 +1,523 @@
+/// A localhost HTTP API serving the accounts, vaults and items of the last sync as JSON
+///
+/// The data is kept in memory by the daemon's syncs, redacted to each vault's privacy level just
+/// like its exported files, so requests never run `op`. Every request needs the bearer token
+/// from the token file, which only the current user can read.
+use crate::filter::{ItemFilter, VaultSelector};
+use crate::op::{AccountDetails, ItemOverview, VaultDetails};
+use crate::privacy::PrivacyLevel;
+use crate::sync::{ExportedVault, Observer, SyncSummary};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tiny_http::{Header, Method, Request, Response};
+
+pub const DEFAULT_PORT: u16 = 8123;
+
+#[derive(Debug)]
+pub enum Error {
+    Bind(u16, String),
+    Token(PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Bind(port, e) => write!(f, "unable to listen on 127.0.0.1:{}: {}", port, e),
+            Error::Token(path, e) => write!(f, "unable to use token file {:?}: {}", path, e),
+        }
+    }
+}
+
+/// `serve-<hash>.token` in the cache folder, one per export path like the control socket
+pub fn default_token_path(export_path: &Path) -> PathBuf {
+    let mut path = crate::cache::cache_dir();
+    path.push(format!(
+        "serve-{:016x}.token",
+        crate::util::stable_hash(export_path.as_os_str().to_string_lossy().as_bytes())
+    ));
+    path
+}
+
+/// The token in `path`, created on first use so clients keep working across restarts. Deleting
+/// the file rotates it. A token other users can read isn't used, as anyone could make requests
+/// with it.
+fn token(path: &Path) -> std::io::Result<String> {
+    match std::fs::File::open(path) {
+        Ok(mut file) => {
+            let mode = file.metadata()?.permissions().mode();
+            let mut token = String::new();
+            file.read_to_string(&mut token)?;
+            if !token.trim().is_empty() {
+                if mode & 0o077 != 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!(
+                            "other users can access it (mode {:o}). Run `chmod 600` on it, or delete it to create a new token",
+                            mode & 0o777
+                        ),
+                    ));
+                }
+                return Ok(token.trim().to_string());
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut bytes = vec![0u8; 32];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    crate::util::write_private_file(path, format!("{}\n", token).as_bytes())?;
+    // Set explicitly so the token never depends on how the file came to be written
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(token)
+}
+
+struct Vault {
+    details: VaultDetails,
+    privacy: PrivacyLevel,
+    items: Vec<ItemOverview>,
+}
+
+#[derive(Default)]
+struct Data {
+    /// Unix time of the last sync, or `None` until the first one is done
+    synced_at: Option<u64>,
+    accounts: Vec<AccountDetails>,
+    /// Vaults by ID, by account ID
+    vaults: BTreeMap<String, BTreeMap<String, Vault>>,
+}
+
+/// What the syncs fetched. Cheap to clone: clones share the same data.
+#[derive(Clone, Default)]
+pub struct Model(Arc<RwLock<Data>>);
+
+impl Model {
+    /// Replaces the accounts, dropping the vaults of accounts that are gone
+    pub fn set_accounts(&self, accounts: &[AccountDetails]) {
+        let mut data = self.0.write().unwrap();
+        data.vaults
+            .retain(|account_id, _| accounts.iter().any(|a| a.id == *account_id));
+        data.accounts = accounts.to_vec();
+    }
+
+    /// True when the vault's items haven't been fetched since the daemon started, so it has to be
+    /// exported even if the cache says it's unchanged
+    pub fn is_missing(&self, account_id: &str, vault_id: &str) -> bool {
+        let data = self.0.read().unwrap();
+        !matches!(data.vaults.get(account_id), Some(vaults) if vaults.contains_key(vault_id))
+    }
+
+    /// Stores a vault's exported items, redacted to its privacy level
+    pub fn set_vault(
+        &self,
+        account_id: &str,
+        vault: &VaultDetails,
+        privacy: PrivacyLevel,
+        mut items: Vec<ItemOverview>,
+    ) -> std::io::Result<()> {
+        let title_key = match privacy {
+            PrivacyLevel::Hashed => crate::privacy::title_key()?,
+            _ => vec![],
+        };
+        for item in items.iter_mut() {
+            crate::privacy::redact_overview(item, privacy, &title_key);
+        }
+
+        let mut data = self.0.write().unwrap();
+        data.vaults
+            .entry(account_id.to_string())
+            .or_default()
+            .insert(
+                vault.id.clone(),
+                Vault {
+                    details: vault.clone(),
+                    privacy,
+                    items,
+                },
+            );
+        Ok(())
+    }
+
+    /// Drops the vaults of the account that weren't exported by the last sync
+    pub fn retain_vaults(&self, account_id: &str, vault_ids: &[&str]) {
+        let mut data = self.0.write().unwrap();
+        if let Some(vaults) = data.vaults.get_mut(account_id) {
+            vaults.retain(|id, _| vault_ids.contains(&id.as_str()));
+        }
+    }
+
+    pub fn remove_vault(&self, account_id: &str, vault_id: &str) {
+        let mut data = self.0.write().unwrap();
+        if let Some(vaults) = data.vaults.get_mut(account_id) {
+            vaults.remove(vault_id);
+        }
+    }
+
+    pub fn synced(&self) {
+        self.0.write().unwrap().synced_at = Some(crate::manifest::now());
+    }
+}
+
+/// Serves what each sync exported
+impl Observer for Model {
+    fn accounts(&mut self, accounts: &[AccountDetails], all: bool) {
+        if all {
+            self.set_accounts(accounts);
+        }
+    }
+
+    fn vault_removed(&mut self, account_id: &str, vault_id: &str, _count: usize) {
+        self.remove_vault(account_id, vault_id);
+    }
+
+    fn needs_export(
+        &mut self,
+        _export_path: &Path,
+        account_id: &str,
+        vault_id: &str,
+        _exported: Option<&crate::manifest::VaultManifest>,
+    ) -> bool {
+        self.is_missing(account_id, vault_id)
+    }
+
+    fn exported(
+        &mut self,
+        account: &AccountDetails,
+        vault: &VaultDetails,
+        exported: &ExportedVault,
+    ) -> Result<(), String> {
+        self.set_vault(
+            &account.id,
+            vault,
+            exported.privacy,
+            exported.overviews.clone(),
+        )
+        .map_err(|err| {
+            format!(
+                "Failed to serve items of vault {} in account {}: {}",
+                vault.id, account.id, err
+            )
+        })
+    }
+
+    fn account_synced(&mut self, account_id: &str, vaults: &[VaultDetails]) {
+        let ids: Vec<&str> = vaults.iter().map(|v| v.id.as_str()).collect();
+        self.retain_vaults(account_id, &ids);
+    }
+
+    fn finished(
+        &mut self,
+        _summary: &mut SyncSummary,
+        _previous: &crate::cache::BookmarkCache,
+        _cache: &crate::cache::BookmarkCache,
+    ) {
+        self.synced();
+    }
+}
+
+#[derive(Serialize)]
+struct VaultResponse<'a> {
+    account_id: &'a str,
+    #[serde(flatten)]
+    vault: &'a VaultDetails,
+    privacy: PrivacyLevel,
+    items: usize,
+}
+
+#[derive(Serialize)]
+struct ItemResponse<'a> {
+    account_id: &'a str,
+    #[serde(flatten)]
+    item: &'a ItemOverview,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Answers requests on 127.0.0.1 for as long as it's kept
+pub struct Server {
+    server: Arc<tiny_http::Server>,
+}
+
+impl Server {
+    pub fn start(port: u16, token_file: &Path, model: Model) -> Result<Server, Error> {
+        let token = token(token_file).map_err(|e| Error::Token(token_file.to_path_buf(), e))?;
+        let server = tiny_http::Server::http(("127.0.0.1", port))
+            .map_err(|e| Error::Bind(port, e.to_string()))?;
+        let server = Arc::new(server);
+
+        {
+            let server = server.clone();
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request, &token, &model);
+                }
+            });
+        }
+
+        Ok(Server { server })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+fn handle(request: Request, token: &str, model: &Model) {
+    let authorized = request.headers().iter().any(|h| {
+        h.field.equiv("Authorization")
+            && matches!(h.value.as_str().strip_prefix("Bearer "), Some(t) if same_token(t.trim(), token))
+    });
+
+    let (status, body) = if !authorized {
+        (401, error("missing or invalid bearer token"))
+    } else if *request.method() != Method::Get {
+        (405, error("only GET is supported"))
+    } else {
+        respond(request.url(), model)
+    };
+
+    let mut response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .with_header(header("Cache-Control", "no-store"));
+    match status {
+        401 => response.add_header(header("WWW-Authenticate", "Bearer")),
+        405 => response.add_header(header("Allow", "GET")),
+        503 => response.add_header(header("Retry-After", "5")),
+        _ => {}
+    }
+
+    // The client may have gone away
+    let _ = request.respond(response);
+}
+
+fn respond(url: &str, model: &Model) -> (u16, String) {
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (url, vec![]),
+    };
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+
+    let data = model.0.read().unwrap();
+    if data.synced_at.is_none() {
+        return (503, error("the first sync hasn't finished yet"));
+    }
+
+    match segments.as_slice() {
+        ["accounts"] => (200, json(&data.accounts)),
+        ["vaults"] => {
+            let account = param(&query, "account");
+            let vaults: Vec<VaultResponse> = data
+                .vaults
+                .iter()
+                .filter(|(account_id, _)| !matches!(account, Some(a) if a != account_id.as_str()))
+                .flat_map(|(account_id, vaults)| {
+                    vaults.values().map(move |v| VaultResponse {
+                        account_id,
+                        vault: &v.details,
+                        privacy: v.privacy,
+                        items: v.items.len(),
+                    })
+                })
+                .collect();
+            (200, json(&vaults))
+        }
+        ["items"] => match items(&data, &query) {
+            Ok(items) => (200, json(&items)),
+            Err(e) => (400, error(&e)),
+        },
+        ["items", account_id, vault_id, item_id] => {
+            let item = data
+                .vaults
+                .get(*account_id)
+                .and_then(|vaults| vaults.get(*vault_id))
+                .and_then(|vault| vault.items.iter().find(|i| i.id == *item_id));
+            match item {
+                Some(item) => (200, json(&ItemResponse { account_id, item })),
+                None => (404, error("no such item")),
+            }
+        }
+        _ => (404, error("unknown path")),
+    }
+}
+
+/// Filters the items by the `account`, `vault`, `q`, `category`, `tag` and `has_url` query
+/// parameters
+fn items<'a>(data: &'a Data, query: &[(String, String)]) -> Result<Vec<ItemResponse<'a>>, String> {
+    let account = param(query, "account");
+    let selector = match param(query, "vault") {
+        Some(selector) => Some(VaultSelector::parse(selector)?),
+        None => None,
+    };
+    let text = param(query, "q").map(|q| q.to_lowercase());
+    let all = |name: &str| -> Vec<String> {
+        query
+            .iter()
+            .filter(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+            .collect()
+    };
+    // Hidden items were never exported, so only the requested filters are applied
+    let filter = ItemFilter {
+        include_categories: all("category"),
+        include_tags: all("tag"),
+        require_urls: matches!(param(query, "has_url"), Some("true") | Some("1")),
+        hide_tag: "".to_string(),
+        ..Default::default()
+    };
+
+    let mut items = vec![];
+    for (account_id, vaults) in data.vaults.iter() {
+        if matches!(account, Some(a) if a != account_id.as_str()) {
+            continue;
+        }
+        for vault in vaults.values() {
+            if matches!(&selector, Some(s) if !s.matches(&vault.details)) {
+                continue;
+            }
+            for item in vault.items.iter() {
+                if !filter.is_item_exported(item) {
+                    continue;
+                }
+                if matches!(&text, Some(text) if !contains_text(item, text)) {
+                    continue;
+                }
+                items.push(ItemResponse { account_id, item });
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Matches the title, description and URLs, ignoring case
+fn contains_text(item: &ItemOverview, text: &str) -> bool {
+    item.title.to_lowercase().contains(text)
+        || matches!(&item.additional_info, Some(info) if info.to_lowercase().contains(text))
+        || item
+            .urls
+            .iter()
+            .any(|u| u.href.to_lowercase().contains(text))
+}
+
+fn param<'a>(query: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    query
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => (decode(k), decode(v)),
+            None => (decode(pair), "".to_string()),
+        })
+        .collect()
+}
+
+/// Percent-decodes a path segment or query value, treating `+` as a space
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Compares every byte so the time taken doesn't reveal how much of the token was right
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn json<T: Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| error(&e.to_string()))
+}
+
+fn error(message: &str) -> String {
+    json(&ErrorResponse {
+        error: message.to_string(),
+    })
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_percent_decoded() {
+        assert_eq!(decode("a%20b+c"), "a b c");
+        assert_eq!(decode("caf%C3%A9"), "café");
+        assert_eq!(decode("%2B"), "+");
+        // Anything that isn't an escape is kept as it is
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(decode("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn queries_are_split_into_decoded_pairs() {
+        assert_eq!(
+            parse_query("q=git+hub&&tag=a%26b&flag&=x"),
+            vec![
+                ("q".to_string(), "git hub".to_string()),
+                ("tag".to_string(), "a&b".to_string()),
+                ("flag".to_string(), String::new()),
+                (String::new(), "x".to_string()),
+            ]
+        );
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(same_token("abc123", "abc123"));
+        assert!(!same_token("abc124", "abc123"));
+        assert!(!same_token("abc12", "abc123"));
+        assert!(!same_token("", "abc123"));
+    }
+}
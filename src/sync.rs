@@ -0,0 +1,787 @@
+/// Exports the vaults that changed since the last sync
+///
+/// Features that keep track of what a sync does, such as events, the served items, the files the
+/// daemon repairs and full resyncs, implement `Observer`. The sync calls every observer at the same
+/// fixed points, so none of their bookkeeping lives in the sync itself.
+use crate::cache::{self, BookmarkCache};
+use crate::config;
+use crate::daemon;
+use crate::encryption;
+use crate::events;
+use crate::filter;
+use crate::heal;
+use crate::hooks;
+use crate::lock;
+use crate::manifest::{self, Manifest, VaultManifest};
+use crate::op::{
+    self, load_all_accounts, load_all_vaults, AccountDetails, ItemOverview, VaultDetails,
+};
+use crate::op7_metadata::{self, create_items, write_items, OP7ItemMetaData};
+use crate::privacy;
+use crate::purge;
+use crate::resync;
+use crate::serve;
+use crate::systemd;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Limits what a sync exports. The default exports every changed vault of the configured accounts.
+#[derive(Clone, Default)]
+pub struct SyncOptions {
+    /// Re-export vaults even when their versions haven't changed
+    pub full: bool,
+    /// Exported account IDs. Every configured account when empty.
+    pub accounts: Vec<String>,
+    pub vault: Option<filter::VaultSelector>,
+    /// Kept up to date with the exported items for `serve`
+    pub model: Option<serve::Model>,
+    /// Kept up to date with the written files so the daemon can restore them
+    pub files: Option<heal::Files>,
+    /// Describe the changes as events, which reads the previously exported files
+    pub events: bool,
+}
+
+impl SyncOptions {
+    /// A full sync of everything also reconciles the export folder with what it exports
+    fn reconcile(&self) -> bool {
+        self.full && self.accounts.is_empty() && self.vault.is_none()
+    }
+}
+
+/// What a sync did, for the daemon to report
+#[derive(Default)]
+pub struct SyncSummary {
+    /// Vaults whose files were written
+    pub exported: usize,
+    /// Failures that didn't stop the rest of the sync
+    pub errors: Vec<String>,
+    /// Items whose files were written or removed
+    pub changes: Vec<hooks::VaultChanges>,
+    /// Only collected when asked for
+    pub events: Vec<events::Event>,
+    /// Accounts that weren't suspended
+    pub accounts: usize,
+    /// What a full sync of every account and vault had to repair
+    pub drift: Option<resync::Drift>,
+    /// Accounts, or the rest of their vaults, skipped as `op` isn't signed in to them, with what
+    /// `op` said. Left for the caller to report.
+    pub signed_out: BTreeMap<String, String>,
+}
+
+impl SyncSummary {
+    fn error(&mut self, message: String) {
+        eprintln!("{}", message);
+        self.errors.push(message);
+    }
+
+    fn signed_out(&mut self, account_id: &str, message: String) {
+        self.errors.push(format!(
+            "Skipped account {} as op isn't signed in: {}",
+            account_id,
+            message.trim_end()
+        ));
+        self.signed_out.insert(account_id.to_string(), message);
+    }
+
+    /// Nothing could be exported as none of the accounts are signed in
+    pub fn all_signed_out(&self) -> bool {
+        self.accounts > 0 && self.signed_out.len() == self.accounts
+    }
+}
+
+/// Why a sync, or a vault, couldn't be exported
+#[derive(Debug)]
+pub enum SyncError {
+    /// `op` isn't signed in or 1Password is locked. Nothing was exported or removed.
+    SignedOut(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::SignedOut(e) => write!(f, "op isn't signed in: {}", e.trim_end()),
+            SyncError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// What was written for a vault
+pub struct ExportedVault {
+    pub privacy: privacy::PrivacyLevel,
+    /// The exported items as `op` listed them
+    pub overviews: Vec<ItemOverview>,
+    /// The version of every exported item by item ID
+    pub versions: BTreeMap<String, usize>,
+    /// Metadata of every exported item, whether or not it was written as JSON
+    pub items: Vec<OP7ItemMetaData>,
+    /// What was written, by file name within the account's folder
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+/// Keeps track of what a sync does. Every method is called at a fixed point of the sync and does
+/// nothing by default.
+pub trait Observer {
+    /// The accounts being synced, suspended ones left out. `all` is false when the sync is limited
+    /// to some of the configured accounts.
+    fn accounts(&mut self, _accounts: &[AccountDetails], _all: bool) {}
+
+    /// Before every file of an account is removed
+    fn removing_account(&mut self, _export_path: &Path, _cache: &BookmarkCache, _account_id: &str) {
+    }
+
+    /// Before every file of a vault is removed
+    fn removing_vault(&mut self, _export_path: &Path, _account_id: &str, _vault_id: &str) {}
+
+    /// After `count` files of a vault were removed
+    fn vault_removed(&mut self, _account_id: &str, _vault_id: &str, _count: usize) {}
+
+    /// Whether a vault the cache and manifest say is up to date has to be exported anyway
+    fn needs_export(
+        &mut self,
+        _export_path: &Path,
+        _account_id: &str,
+        _vault_id: &str,
+        _exported: Option<&VaultManifest>,
+    ) -> bool {
+        false
+    }
+
+    /// Before a vault's files are rewritten. `same_settings` is true when they were written with
+    /// the current settings, so only changes in 1Password should change them.
+    fn exporting(
+        &mut self,
+        _export_path: &Path,
+        _account_id: &str,
+        _vault_id: &str,
+        _same_settings: bool,
+    ) {
+    }
+
+    /// After a vault's files were written. An error fails the vault, which is left out of the
+    /// cache so the next sync exports it again, and later observers aren't told about it.
+    fn exported(
+        &mut self,
+        _account: &AccountDetails,
+        _vault: &VaultDetails,
+        _exported: &ExportedVault,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Vaults that are no longer in 1Password and whose files should be removed. Only asked for
+    /// accounts whose vaults were listed.
+    fn vanished_vaults(
+        &mut self,
+        _export_path: &Path,
+        _account_id: &str,
+        _vaults: &[VaultDetails],
+        _cache: &BookmarkCache,
+        _manifest: &Manifest,
+    ) -> Vec<String> {
+        vec![]
+    }
+
+    /// After every vault of an account was synced. `vaults` are the ones now exported.
+    fn account_synced(&mut self, _account_id: &str, _vaults: &[VaultDetails]) {}
+
+    /// Once the sync is done and the cache and manifest are saved. `previous` is the cache the sync
+    /// started with.
+    fn finished(
+        &mut self,
+        _summary: &mut SyncSummary,
+        _previous: &BookmarkCache,
+        _cache: &BookmarkCache,
+    ) {
+    }
+}
+
+type Observers = Vec<Box<dyn Observer>>;
+
+/// The observers a sync with these options keeps up to date. The served items come first so a
+/// vault that can't be served isn't recorded by the others.
+fn observers(
+    settings: &config::Settings,
+    options: &SyncOptions,
+    cache: &BookmarkCache,
+) -> Observers {
+    let mut observers: Observers = vec![];
+    if let Some(model) = &options.model {
+        observers.push(Box::new(model.clone()));
+    }
+    if let Some(files) = &options.files {
+        observers.push(Box::new(files.clone()));
+    }
+    if options.events {
+        observers.push(Box::new(events::ItemMetadata::default()));
+    }
+    if options.reconcile() {
+        observers.push(Box::new(resync::Reconciler::new(cache, &settings.formats)));
+    }
+    observers
+}
+
+/// Exports every vault that changed since the cache was written, or every vault for a full sync.
+/// Stops between vaults when the daemon is asked to shut down.
+pub fn run(settings: &config::Settings, options: &SyncOptions) -> Result<SyncSummary, SyncError> {
+    let export_path = &settings.export_path;
+
+    // Held until the cache has been saved so concurrent runs can't interleave their output
+    let _lock = lock::acquire(export_path, settings.lock_policy)
+        .map_err(|e| SyncError::Failed(e.to_string()))?;
+
+    let cache = cache::load(export_path);
+    if options.reconcile() {
+        println!(
+            "Ignoring cached vault versions and reconciling the export folder for a full sync"
+        );
+    } else if options.full {
+        println!("Ignoring cached vault versions for a full sync");
+    }
+    let mut run = Run {
+        settings,
+        options,
+        export_path,
+        observers: observers(settings, options, &cache),
+        previous: cache.clone(),
+        cache,
+        manifest: manifest::load(export_path),
+        vault_names: HashMap::new(),
+        summary: SyncSummary::default(),
+    };
+    for (account_id, vaults) in run.cache.vaults_by_account_id.iter() {
+        for vault in vaults.iter() {
+            run.vault_names
+                .insert((account_id.clone(), vault.id.clone()), vault.name.clone());
+        }
+    }
+
+    // Checked before anything is removed, so being signed out never costs an export
+    let mut accounts = load_all_accounts(&settings.accounts, settings.strict_accounts).map_err(
+        |err| match err {
+            op::Error::Auth(e) => SyncError::SignedOut(e),
+            err => SyncError::Failed(format!("failed to load accounts: {}", err)),
+        },
+    )?;
+
+    if !options.accounts.is_empty() {
+        if let Some(missing) = options
+            .accounts
+            .iter()
+            .find(|id| !accounts.iter().any(|a| a.id == **id))
+        {
+            return Err(SyncError::Failed(format!(
+                "account {} is not being exported",
+                missing
+            )));
+        }
+        accounts.retain(|a| options.accounts.contains(&a.id));
+    }
+
+    if settings.auto_purge {
+        run.purge_vanished_accounts();
+    }
+    let active: Vec<AccountDetails> = accounts
+        .iter()
+        .filter(|a| !purge::is_suspended(a))
+        .cloned()
+        .collect();
+    for observer in run.observers.iter_mut() {
+        observer.accounts(&active, options.accounts.is_empty());
+    }
+
+    println!(
+        "Exporting bookmarks for accounts {:?}",
+        accounts
+            .iter()
+            .map(|a| a.id.clone())
+            .collect::<Vec<String>>()
+    );
+
+    let vaults_by_account = run.load_vaults(&accounts);
+    for (account, vaults) in vaults_by_account.iter() {
+        run.sync_account(account, vaults);
+    }
+    println!("Metadata files written to {:?}.", export_path);
+
+    Ok(run.finish())
+}
+
+/// The state of a sync as it goes
+struct Run<'a> {
+    settings: &'a config::Settings,
+    options: &'a SyncOptions,
+    export_path: &'a Path,
+    observers: Observers,
+    /// The cache as it was when the sync started
+    previous: BookmarkCache,
+    cache: BookmarkCache,
+    manifest: Manifest,
+    /// Names of the vaults that were or are exported, by account and vault ID
+    vault_names: HashMap<(String, String), String>,
+    summary: SyncSummary,
+}
+
+impl<'a> Run<'a> {
+    /// Purges exported accounts that `op account list` no longer knows about. Nothing is purged
+    /// when the list can't be loaded.
+    fn purge_vanished_accounts(&mut self) {
+        let listed = match op::find_accounts() {
+            Ok(listed) => listed,
+            Err(e) => {
+                eprintln!("Unable to check for removed accounts: {}", e);
+                return;
+            }
+        };
+
+        let exported = purge::exported_account_ids(self.export_path, &self.cache, &self.manifest);
+        for account_id in purge::vanished_accounts(&exported, &listed) {
+            match self.purge_account(&account_id) {
+                Ok(count) => println!(
+                    "Purged account {} as it's no longer in `op account list`, removed {} files",
+                    account_id, count
+                ),
+                Err(e) => eprintln!("Failed to purge account {}: {}", account_id, e),
+            }
+        }
+    }
+
+    fn purge_account(&mut self, account_id: &str) -> std::io::Result<usize> {
+        for observer in self.observers.iter_mut() {
+            observer.removing_account(self.export_path, &self.cache, account_id);
+        }
+        purge::purge_account(
+            self.export_path,
+            &mut self.cache,
+            &mut self.manifest,
+            account_id,
+        )
+    }
+
+    /// Lists the vaults of every account that isn't suspended, purging suspended ones when asked to
+    fn load_vaults(
+        &mut self,
+        accounts: &[AccountDetails],
+    ) -> HashMap<AccountDetails, Vec<VaultDetails>> {
+        let mut vaults_by_account = HashMap::new();
+
+        for account in accounts.iter() {
+            if purge::is_suspended(account) {
+                if self.settings.auto_purge {
+                    match self.purge_account(&account.id) {
+                        Ok(count) => println!(
+                            "Purged suspended account {}, removed {} files",
+                            account.id, count
+                        ),
+                        Err(e) => self.summary.error(format!(
+                            "Failed to purge suspended account {}: {}",
+                            account.id, e
+                        )),
+                    }
+                } else {
+                    eprintln!(
+                        "Skipping suspended account {}. Use `opbookmarks purge --account {}` to remove its bookmarks.",
+                        account.id, account.id
+                    );
+                }
+                continue;
+            }
+            self.summary.accounts += 1;
+
+            match load_all_vaults(&account.id) {
+                Ok(vaults) => {
+                    vaults_by_account.insert(account.clone(), vaults);
+                }
+                Err(op::Error::Auth(e)) => self.summary.signed_out(&account.id, e),
+                Err(err) => self.summary.error(format!(
+                    "Failed to load vaults for account {}: {:?}",
+                    account.id, err
+                )),
+            }
+        }
+        vaults_by_account
+    }
+
+    /// Exports each vault of the account that has changed. A vault's new version is only
+    /// committed to the cache once its metadata has been written so failed vaults are retried
+    /// next time.
+    fn sync_account(&mut self, account: &AccountDetails, vaults: &[VaultDetails]) {
+        let mut exported_vaults: Vec<VaultDetails> = vec![];
+
+        for vault in vaults.iter() {
+            self.vault_names
+                .insert((account.id.clone(), vault.id.clone()), vault.name.clone());
+            systemd::watchdog();
+            if let Some(exported) = self.sync_vault(account, vault) {
+                exported_vaults.push(exported);
+            }
+        }
+
+        // Files of vaults deleted in 1Password are otherwise left behind
+        let listed = !self.summary.signed_out.contains_key(&account.id);
+        if listed && !daemon::shutdown_requested() {
+            let mut vanished: Vec<String> = vec![];
+            for observer in self.observers.iter_mut() {
+                vanished.extend(observer.vanished_vaults(
+                    self.export_path,
+                    &account.id,
+                    vaults,
+                    &self.cache,
+                    &self.manifest,
+                ));
+            }
+            vanished.sort();
+            vanished.dedup();
+            for vault_id in vanished.iter() {
+                self.remove_vanished_vault(&account.id, vault_id);
+            }
+        }
+
+        for observer in self.observers.iter_mut() {
+            observer.account_synced(&account.id, &exported_vaults);
+        }
+        self.cache
+            .vaults_by_account_id
+            .insert(account.id.clone(), exported_vaults);
+    }
+
+    /// Exports the vault if it needs to be. Returns the vault as it should be cached, which is
+    /// its previous version when it's left for another run, or nothing when it isn't exported.
+    fn sync_vault(
+        &mut self,
+        account: &AccountDetails,
+        vault: &VaultDetails,
+    ) -> Option<VaultDetails> {
+        let export_path = self.export_path;
+        let settings = self.settings;
+        let options = self.options;
+
+        let in_scope = match &options.vault {
+            Some(selector) => selector.matches(vault),
+            None => true,
+        };
+        let signed_out = self.summary.signed_out.contains_key(&account.id);
+        if daemon::shutdown_requested() || !in_scope || signed_out {
+            // Left for another run to export
+            return self.cache.vault(&account.id, &vault.id).cloned();
+        }
+
+        if !filter::is_vault_exported(&settings.vault_filters, account, vault) {
+            self.remove_excluded_vault(&account.id, &vault.id);
+            return None;
+        }
+
+        // Files written at a different privacy level, in other formats, with other item filters
+        // or with another title hash key are rewritten even when nothing changed
+        let privacy = settings.privacy.level_for(account, vault);
+        let exported = self.manifest.vault(&account.id, &vault.id);
+        let exported_privacy = exported.map(|v| v.privacy);
+        let formats_match = matches!(exported, Some(v) if v.formats == settings.formats);
+        let item_filter = settings.item_filter.fingerprint();
+        let filter_matches =
+            matches!(exported, Some(v) if v.item_filter.as_ref() == Some(&item_filter));
+        let title_key = privacy::title_key_fingerprint(privacy);
+        let title_key_matches = matches!(exported, Some(v) if v.title_key == title_key);
+        let same_settings = exported_privacy == Some(privacy)
+            && formats_match
+            && filter_matches
+            && title_key_matches;
+        let export_needed = options.full
+            || !same_settings
+            || self.cache.has_changed(&account.id, vault)
+            || self
+                .observers
+                .iter_mut()
+                .any(|o| o.needs_export(export_path, &account.id, &vault.id, exported));
+        if !export_needed {
+            println!("No changes detected in {}::{}", account.id, vault.id);
+            if let Some(exported) = self.manifest.vault_mut(&account.id, &vault.id) {
+                exported.touch(settings.ttl);
+            }
+            return Some(vault.clone());
+        }
+        if let Some(exported_privacy) = exported_privacy {
+            if exported_privacy != privacy {
+                println!(
+                    "Privacy level of {}::{} changed from {} to {}",
+                    account.id, vault.id, exported_privacy, privacy
+                );
+            }
+        }
+
+        for observer in self.observers.iter_mut() {
+            observer.exporting(export_path, &account.id, &vault.id, same_settings);
+        }
+        let observers = &mut self.observers;
+        let result =
+            export_vault(export_path, account, vault, settings, privacy).and_then(|exported| {
+                for observer in observers.iter_mut() {
+                    observer
+                        .exported(account, vault, &exported)
+                        .map_err(SyncError::Failed)?;
+                }
+                Ok(exported)
+            });
+        match result {
+            Ok(exported) => {
+                self.summary.exported += 1;
+                self.manifest
+                    .accounts
+                    .entry(account.id.clone())
+                    .or_default()
+                    .insert(
+                        vault.id.clone(),
+                        VaultManifest::new(
+                            privacy,
+                            &settings.formats,
+                            item_filter,
+                            title_key,
+                            exported.versions.len(),
+                            settings.ttl,
+                            &exported.files,
+                        ),
+                    );
+                self.cache
+                    .set_item_versions(&account.id, &vault.id, exported.versions);
+                Some(vault.clone())
+            }
+            Err(err) => {
+                match err {
+                    // The account's other vaults are left for the next sync too
+                    SyncError::SignedOut(e) => self.summary.signed_out(&account.id, e),
+                    SyncError::Failed(e) => self.summary.error(e),
+                }
+                self.cache.vault(&account.id, &vault.id).cloned()
+            }
+        }
+    }
+
+    /// Excluded vaults are never listed with `op` and anything previously exported for them is
+    /// removed. Dropping them from the cache re-exports them if they're included again later.
+    fn remove_excluded_vault(&mut self, account_id: &str, vault_id: &str) {
+        for observer in self.observers.iter_mut() {
+            observer.removing_vault(self.export_path, account_id, vault_id);
+        }
+        let removed = match remove_exported_vault(self.export_path, account_id, vault_id) {
+            Ok(0) => {
+                println!("Skipping excluded vault {}::{}", account_id, vault_id);
+                0
+            }
+            Ok(count) => {
+                println!(
+                    "Skipping excluded vault {}::{}, removed {} previously exported files",
+                    account_id, vault_id, count
+                );
+                count
+            }
+            Err(e) => {
+                self.summary.error(format!(
+                    "Failed to remove exported files for excluded vault {}::{}: {}",
+                    account_id, vault_id, e
+                ));
+                0
+            }
+        };
+        self.forget_vault(account_id, vault_id, removed);
+    }
+
+    /// Removes the files of a vault an observer found was deleted in 1Password
+    fn remove_vanished_vault(&mut self, account_id: &str, vault_id: &str) {
+        for observer in self.observers.iter_mut() {
+            observer.removing_vault(self.export_path, account_id, vault_id);
+        }
+        match remove_exported_vault(self.export_path, account_id, vault_id) {
+            Ok(0) => self.forget_vault(account_id, vault_id, 0),
+            Ok(count) => {
+                println!(
+                    "Removed {} stray files of {}::{}, which no longer exists",
+                    count, account_id, vault_id
+                );
+                self.forget_vault(account_id, vault_id, count);
+            }
+            Err(e) => self.summary.error(format!(
+                "Failed to remove files of deleted vault {}::{}: {}",
+                account_id, vault_id, e
+            )),
+        }
+    }
+
+    fn forget_vault(&mut self, account_id: &str, vault_id: &str, removed: usize) {
+        self.manifest.remove_vault(account_id, vault_id);
+        self.cache.remove_item_versions(account_id, vault_id);
+        for observer in self.observers.iter_mut() {
+            observer.vault_removed(account_id, vault_id, removed);
+        }
+    }
+
+    /// Describes the changes and saves the cache and manifest. Accounts whose vaults couldn't be
+    /// loaded keep their previous entries.
+    fn finish(mut self) -> SyncSummary {
+        self.summary.changes = hooks::changes(
+            &self.previous.item_versions,
+            &self.cache.item_versions,
+            &self.vault_names,
+        );
+
+        cache::save(&self.cache);
+        self.manifest.synced_at = Some(manifest::now());
+        self.manifest.ttl = self.settings.ttl;
+        if let Err(e) = manifest::save(self.export_path, &mut self.manifest) {
+            self.summary
+                .error(format!("Unable to write manifest: {}", e));
+        }
+        for observer in self.observers.iter_mut() {
+            observer.finished(&mut self.summary, &self.previous, &self.cache);
+        }
+        self.summary
+    }
+}
+
+/// Writes the vault's files
+fn export_vault(
+    export_path: &Path,
+    account: &AccountDetails,
+    vault: &VaultDetails,
+    settings: &config::Settings,
+    privacy: privacy::PrivacyLevel,
+) -> Result<ExportedVault, SyncError> {
+    let mut overviews = op::item_overviews(&account.id, &vault.id).map_err(|err| match err {
+        op::Error::Auth(e) => SyncError::SignedOut(e),
+        err => SyncError::Failed(format!(
+            "Failed to load item overviews for vault {} in account {}: {:?}",
+            vault.id, account.id, err
+        )),
+    })?;
+
+    let total = overviews.len();
+    overviews.retain(|i| settings.item_filter.is_item_exported(i));
+    if overviews.len() < total {
+        println!(
+            "Filtered out {} of {} items in {}::{}",
+            total - overviews.len(),
+            total,
+            account.id,
+            vault.id
+        );
+    }
+
+    let items = create_items(&overviews, vault, account, privacy).map_err(|err| {
+        SyncError::Failed(format!(
+            "Failed to create metadata for vault {} in account {}: {}",
+            vault.id, account.id, err
+        ))
+    })?;
+
+    // Formats that are no longer used have their files removed so plain text doesn't linger
+    // once a vault is only exported encrypted
+    let json_items: &[OP7ItemMetaData] =
+        if settings.formats.contains(&encryption::ExportFormat::Json) {
+            &items
+        } else {
+            &[]
+        };
+    let mut files = write_items(export_path, json_items).map_err(|err| {
+        SyncError::Failed(format!(
+            "Failed to write metadata for vault {} in account {}: {}",
+            vault.id, account.id, err
+        ))
+    })?;
+
+    // Remove files for items that were deleted or are now filtered out
+    let keep: Vec<&str> = json_items.iter().map(|i| i.uuid.as_str()).collect();
+    op7_metadata::remove_items_except(export_path, &account.id, &vault.id, &keep).map_err(
+        |err| {
+            SyncError::Failed(format!(
+                "Failed to remove stale metadata for vault {} in account {}: {}",
+                vault.id, account.id, err
+            ))
+        },
+    )?;
+
+    match &settings.encryption {
+        Some(encryption) if settings.formats.contains(&encryption::ExportFormat::Age) => {
+            let index =
+                encryption::write_index(export_path, &account.id, &vault.id, &items, encryption)
+                    .map_err(|err| {
+                        SyncError::Failed(format!(
+                            "Failed to write encrypted index for vault {} in account {}: {}",
+                            vault.id, account.id, err
+                        ))
+                    })?;
+            files.insert(
+                format!("{}.{}", vault.id, encryption::INDEX_EXTENSION),
+                index,
+            );
+        }
+        _ => {
+            encryption::remove_index(export_path, &account.id, &vault.id).map_err(|err| {
+                SyncError::Failed(format!(
+                    "Failed to remove encrypted index for vault {} in account {}: {}",
+                    vault.id, account.id, err
+                ))
+            })?;
+        }
+    }
+
+    let versions = overviews
+        .iter()
+        .map(|i| (i.id.clone(), i.version))
+        .collect();
+
+    Ok(ExportedVault {
+        privacy,
+        overviews,
+        versions,
+        items,
+        files,
+    })
+}
+
+/// Removes every file exported for a vault, in any format. Returns how many were removed.
+pub fn remove_exported_vault(
+    export_path: &Path,
+    account_id: &str,
+    vault_id: &str,
+) -> std::io::Result<usize> {
+    let count = op7_metadata::remove_vault(export_path, account_id, vault_id)?;
+    let index_removed = encryption::remove_index(export_path, account_id, vault_id)?;
+    Ok(count + index_removed as usize)
+}
+
+/// Tells the configured hooks how a sync went. Runs once the export lock has been released.
+pub fn run_hooks(
+    settings: &config::Settings,
+    options: &SyncOptions,
+    result: &Result<SyncSummary, SyncError>,
+) {
+    let error = match result {
+        Err(e) => Some(e.to_string()),
+        Ok(_) => None,
+    };
+    let (status, vaults, errors) = match result {
+        Ok(summary) if summary.errors.is_empty() => {
+            ("ok", &summary.changes[..], &summary.errors[..])
+        }
+        Ok(summary) if summary.all_signed_out() => {
+            ("signed-out", &summary.changes[..], &summary.errors[..])
+        }
+        Ok(summary) => ("partial", &summary.changes[..], &summary.errors[..]),
+        Err(SyncError::SignedOut(_)) => ("signed-out", &[][..], &[][..]),
+        Err(SyncError::Failed(_)) => ("failed", &[][..], &[][..]),
+    };
+
+    hooks::run_all(
+        &settings.hooks,
+        &hooks::Summary {
+            export_path: &settings.export_path,
+            profile: settings.profile.as_deref(),
+            synced_at: manifest::now(),
+            full: options.full,
+            status,
+            vaults,
+            errors,
+            error: error.as_deref(),
+            drift: result.as_ref().ok().and_then(|s| s.drift.as_ref()),
+        },
+    );
+}
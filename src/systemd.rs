@@ -0,0 +1,274 @@
+/// Runs the watch daemon as a systemd user service: writes the unit and speaks `sd_notify`
+///
+/// Notifications are datagrams sent to the socket in `NOTIFY_SOCKET`, which systemd only sets for
+/// `Type=notify` services. Without it every notification is skipped, so the daemon behaves the
+/// same outside of systemd.
+use crate::config::Settings;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// Long enough for a sync of several large vaults, during which there are no heartbeats
+const WATCHDOG_SEC: &str = "15min";
+
+static LAST_WATCHDOG: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Tells systemd the daemon has finished starting, or reloading
+pub fn ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// Free-form status shown by `systemctl --user status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+pub fn reloading() {
+    notify("RELOADING=1\nSTATUS=Reloading configuration");
+}
+
+pub fn stopping() {
+    notify("STOPPING=1\nSTATUS=Stopping");
+}
+
+/// Sends a heartbeat when the service has `WatchdogSec` set, at most every half interval. Call it
+/// often: systemd restarts the daemon when heartbeats stop.
+pub fn watchdog() {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval / 2,
+        None => return,
+    };
+
+    let mut last = LAST_WATCHDOG.lock().unwrap();
+    if matches!(*last, Some(last) if last.elapsed() < interval) {
+        return;
+    }
+    *last = Some(Instant::now());
+    notify("WATCHDOG=1");
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var(WATCHDOG_USEC_ENV).ok()?.parse::<u64>().ok()?;
+
+    // The variables are inherited by child processes that aren't the service's main process
+    if let Ok(pid) = std::env::var(WATCHDOG_PID_ENV) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec))
+}
+
+fn notify(state: &str) {
+    let socket = match std::env::var_os(NOTIFY_SOCKET_ENV) {
+        Some(socket) if !socket.is_empty() => socket,
+        _ => return,
+    };
+
+    if let Err(e) = send(&socket, state) {
+        eprintln!("Unable to notify systemd via {:?}: {}", socket, e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = socket.as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    if path.len() >= addr.sun_path.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "socket path is too long",
+        ));
+    }
+
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (i, byte) in path.iter().enumerate() {
+        addr.sun_path[i] = *byte as libc::c_char;
+    }
+    // Abstract sockets are given with a leading @ but addressed with a leading NUL
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let sent = unsafe {
+        libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    let result = if sent < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+
+    unsafe { libc::close(fd) };
+    result
+}
+
+/// systemd only runs on Linux
+#[cfg(not(target_os = "linux"))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// `opbookmarks.service`, or `opbookmarks-<profile>.service` so each profile can have its own
+pub fn unit_name(settings: &Settings) -> String {
+    match &settings.profile {
+        Some(profile) => format!("opbookmarks-{}.service", profile),
+        None => "opbookmarks.service".to_string(),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/systemd/user/<name>`, falling back to `~/.config/systemd/user/<name>`
+pub fn unit_path(name: &str) -> PathBuf {
+    let mut path = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut p = dirs::home_dir().unwrap();
+            p.push(".config");
+            p
+        }
+    };
+    path.push("systemd/user");
+    path.push(name);
+    path
+}
+
+/// Folders the service writes to. Everything else is read-only under `ProtectSystem=strict` and
+/// `ProtectHome=read-only`. Optional folders are prefixed with `-` so a missing one doesn't stop
+/// the service from starting.
+pub fn writable_paths(settings: &Settings) -> Vec<String> {
+    let mut paths = vec![
+        absolute(&settings.export_path).display().to_string(),
+        crate::cache::cache_dir().display().to_string(),
+        crate::privacy::data_dir().display().to_string(),
+    ];
+
+    // `op` keeps its configuration and session state here
+    let mut op_config = dirs::home_dir().unwrap();
+    op_config.push(".config/op");
+    paths.push(format!("-{}", op_config.display()));
+
+    if settings.on_expiry == crate::gc::ExpiryAction::Quarantine {
+        let quarantine = crate::gc::quarantine_path(&absolute(&settings.export_path));
+        paths.push(format!("-{}", quarantine.display()));
+    }
+    let files = settings
+        .pid_file
+        .iter()
+        .chain([&settings.control_socket])
+        .chain(settings.events_fifo.iter())
+        .chain(settings.events_socket.iter());
+    for file in files {
+        if let Some(folder) = absolute(file).parent() {
+            let folder = folder.display().to_string();
+            if !paths.iter().any(|p| p.trim_start_matches('-') == folder) {
+                paths.push(format!("-{}", folder));
+            }
+        }
+    }
+
+    paths
+}
+
+/// A hardened user unit running `watch` with the current configuration file, profile and export
+/// path
+pub fn unit(settings: &Settings, exe: &Path) -> String {
+    let mut command = vec![exe.display().to_string(), "watch".to_string()];
+    if let Some(config_path) = &settings.config_path {
+        command.push("--config".to_string());
+        command.push(absolute(config_path).display().to_string());
+    }
+    if let Some(profile) = &settings.profile {
+        command.push("--profile".to_string());
+        command.push(profile.clone());
+    }
+    // Pinned so the sandbox below always matches where the daemon writes
+    command.push("--export-path".to_string());
+    command.push(absolute(&settings.export_path).display().to_string());
+
+    // Only command lines expand variables
+    let exec_start: Vec<String> = command
+        .iter()
+        .map(|arg| quote(&arg.replace('$', "$$")))
+        .collect();
+    let writable: Vec<String> = writable_paths(settings).iter().map(|p| quote(p)).collect();
+
+    format!(
+        "[Unit]
+Description=Export 1Password item metadata for apps
+Documentation=https://github.com/dteare/opbookmarks
+
+[Service]
+Type=notify
+ExecStart={exec_start}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+RestartSec=30
+WatchdogSec={watchdog}
+TimeoutStopSec=60
+
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=read-only
+ReadWritePaths={writable}
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+SystemCallArchitectures=native
+UMask=0077
+
+[Install]
+WantedBy=default.target
+",
+        exec_start = exec_start.join(" "),
+        watchdog = WATCHDOG_SEC,
+        writable = writable.join(" "),
+    )
+}
+
+/// Services start in a different working directory
+fn absolute(path: &Path) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Quotes a word for a unit file, escaping the specifiers systemd would expand
+fn quote(word: &str) -> String {
+    let escaped = word.replace('%', "%%");
+    if escaped.is_empty()
+        || escaped
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';')
+    {
+        format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        escaped
+    }
+}
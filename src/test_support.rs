@@ -0,0 +1,72 @@
+/// Fixtures shared by the unit tests of several modules
+use crate::op7_metadata::OP7ItemMetaData;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// A folder under the system temp folder that is removed when dropped
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    /// `name` tells apart the folders of different tests' modules
+    pub fn new(name: &str) -> TempDir {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "opbookmarks-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path.canonicalize().unwrap())
+    }
+
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A vault's item versions: account ID, vault ID and `(item ID, version)` pairs
+pub type VaultItems<'a> = (&'a str, &'a str, &'a [(&'a str, usize)]);
+
+/// Item versions laid out as in `BookmarkCache::item_versions`
+pub fn item_versions(
+    vaults: &[VaultItems],
+) -> HashMap<String, HashMap<String, BTreeMap<String, usize>>> {
+    let mut all: HashMap<String, HashMap<String, BTreeMap<String, usize>>> = HashMap::new();
+    for (account_id, vault_id, items) in vaults {
+        all.entry(account_id.to_string()).or_default().insert(
+            vault_id.to_string(),
+            items.iter().map(|(id, v)| (id.to_string(), *v)).collect(),
+        );
+    }
+    all
+}
+
+/// Metadata with only the IDs and title filled in
+pub fn item(account_id: &str, vault_id: &str, item_id: &str, title: &str) -> OP7ItemMetaData {
+    OP7ItemMetaData {
+        uuid: item_id.to_string(),
+        profile_uuid: account_id.to_string(),
+        vault_uuid: vault_id.to_string(),
+        category_uuid: "001".to_string(),
+        item_title: title.to_string(),
+        item_description: String::new(),
+        website_urls: vec![],
+        account_name: String::new(),
+        vault_name: vault_id.to_string(),
+        category_plural_name: "Logins".to_string(),
+        category_singular_name: "Login".to_string(),
+        modified_at: 0,
+        created_at: 0,
+        deep_link: None,
+    }
+}
@@ -0,0 +1,279 @@
+/// What makes the `watch` daemon sync, other than control requests
+///
+/// Changes to the 1Password data folder, or to vault versions when polling, a refresh before the
+/// export expires, scheduled full resyncs, SIGUSR1, resuming after a pause and retries while `op`
+/// isn't signed in. The export folder is repaired while waiting for any of them.
+use crate::cache;
+use crate::config::Settings;
+use crate::daemon::{self, Daemon};
+use crate::heal;
+use crate::manifest;
+use crate::op;
+use crate::poll;
+use crate::resync::Schedule;
+use crate::systemd;
+use crate::util;
+use crate::watch::ChangeDetector;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// A reason to sync
+pub struct Trigger {
+    pub reason: String,
+    pub full: bool,
+}
+
+pub struct Triggers {
+    /// Polling when there's no folder to watch or the watcher failed
+    detector: Option<ChangeDetector>,
+    interval: poll::PollInterval,
+    reported: poll::Reported,
+    next_poll: Instant,
+    /// Half the ttl, so the export doesn't expire while nothing changes
+    refresh_every: Option<Duration>,
+    last_sync: Instant,
+    was_paused: bool,
+    healer: Option<heal::Healer>,
+    resync: Option<Schedule>,
+    next_resync: Option<u64>,
+}
+
+impl Triggers {
+    pub fn new(settings: &Settings, path: Option<&Path>, files: heal::Files) -> Triggers {
+        let detector = match path {
+            Some(path) => match ChangeDetector::new(path, settings.watch_triggers.clone()) {
+                Ok(detector) => {
+                    println!("Watching 1Password 8 data folder for changes ({:?})", path);
+                    Some(detector)
+                }
+                Err(e) => {
+                    eprintln!("Unable to watch {:?}: {:?}", path, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let interval =
+            poll::PollInterval::new(settings.poll_interval.unwrap_or(poll::DEFAULT_INTERVAL));
+        if detector.is_none() {
+            println!(
+                "Polling 1Password for changes every {}",
+                util::format_duration(interval.current().as_secs())
+            );
+        }
+
+        let healer = match heal::Healer::new(&settings.export_path, files) {
+            Ok(healer) => {
+                println!("Watching {:?} for files to repair", settings.export_path);
+                Some(healer)
+            }
+            Err(e) => {
+                eprintln!(
+                    "Unable to watch {:?} for files to repair: {:?}",
+                    settings.export_path, e
+                );
+                None
+            }
+        };
+
+        if let Some(schedule) = &settings.resync {
+            println!("Running a full resync {}", schedule);
+        }
+
+        Triggers {
+            detector,
+            next_poll: Instant::now() + interval.current(),
+            interval,
+            reported: poll::Reported::default(),
+            refresh_every: settings
+                .ttl
+                .map(|ttl| Duration::from_secs(std::cmp::max(ttl / 2, 1))),
+            last_sync: Instant::now(),
+            was_paused: false,
+            healer,
+            resync: settings.resync,
+            next_resync: settings
+                .resync
+                .map(|schedule| schedule.next(manifest::now())),
+        }
+    }
+
+    pub fn mode(&self) -> &'static str {
+        match self.detector {
+            Some(_) => "watching",
+            None => "polling",
+        }
+    }
+
+    /// Unix time of the next scheduled full resync
+    pub fn next_resync(&self) -> Option<u64> {
+        self.next_resync
+    }
+
+    /// Restarts the wait for the ttl refresh
+    pub fn synced(&mut self) {
+        self.last_sync = Instant::now();
+    }
+
+    /// Waits up to `daemon::SIGNAL_CHECK_INTERVAL`, or until the next poll, for a reason to sync.
+    /// Returns `None` when there's none yet so the caller can handle signals and requests.
+    pub fn next(&mut self, settings: &Settings, daemon: &mut Daemon) -> Option<Trigger> {
+        let paused = daemon.is_paused();
+
+        // Files changed while paused are left until resumed, so they can be edited by hand
+        let repaired = match &mut self.healer {
+            Some(active) if !paused => Some(active.check()),
+            _ => None,
+        };
+        match repaired {
+            Some(Ok(drift)) if drift.total() > 0 => {
+                daemon.update(|status| status.repaired.add(&drift));
+            }
+            Some(Err(e)) => {
+                eprintln!("Stopped repairing the export folder: {:?}", e);
+                self.healer = None;
+            }
+            _ => {}
+        }
+
+        let full = daemon::take_full_sync();
+        if paused && !full {
+            // Changes are dropped while paused and caught up on by a sync when resumed
+            self.was_paused = true;
+            self.wait(daemon::SIGNAL_CHECK_INTERVAL);
+            return None;
+        }
+
+        let resync_in = self
+            .next_resync
+            .map(|at| Duration::from_secs(at.saturating_sub(manifest::now())));
+        let resync = matches!(resync_in, Some(wait) if wait.is_zero());
+
+        let reason = if full {
+            Some("Full sync requested".to_string())
+        } else if resync {
+            self.next_resync = self.resync.map(|schedule| schedule.next(manifest::now()));
+            Some("Scheduled full resync".to_string())
+        } else if daemon.sign_in.retry_due() {
+            Some("Checking whether op is signed in again".to_string())
+        } else if self.was_paused && !paused {
+            self.was_paused = false;
+            Some("Resumed".to_string())
+        } else if daemon.sign_in.is_signed_out() {
+            // Changes are left for the next retry, which exports whatever changed meanwhile
+            let wait = match daemon.sign_in.retry_in() {
+                Some(retry_in) => std::cmp::min(retry_in, daemon::SIGNAL_CHECK_INTERVAL),
+                None => daemon::SIGNAL_CHECK_INTERVAL,
+            };
+            self.wait(wait);
+            return None;
+        } else {
+            self.next_change(settings, daemon, resync_in)
+        };
+
+        let reason = match reason {
+            Some(reason) => reason,
+            None => match self.refresh_every {
+                Some(every) if self.last_sync.elapsed() >= every => {
+                    "Refreshing before the export expires".to_string()
+                }
+                _ => return None,
+            },
+        };
+        Some(Trigger {
+            reason,
+            full: full || resync,
+        })
+    }
+
+    /// Waits for the data folder to change, or polls when it's time to
+    fn next_change(
+        &mut self,
+        settings: &Settings,
+        daemon: &mut Daemon,
+        resync_in: Option<Duration>,
+    ) -> Option<String> {
+        match &self.detector {
+            Some(active) => match active.next_change(Some(daemon::SIGNAL_CHECK_INTERVAL)) {
+                Ok(Some(changed)) => Some(format!(
+                    "1Password 8 data changed ({} files)",
+                    changed.len()
+                )),
+                Ok(None) => None,
+                Err(e) => {
+                    eprintln!(
+                        "Stopped watching for changes: {:?}. Polling every {} instead.",
+                        e,
+                        util::format_duration(self.interval.current().as_secs())
+                    );
+                    self.detector = None;
+                    self.next_poll = Instant::now();
+                    None
+                }
+            },
+            None if Instant::now() < self.next_poll => {
+                let refresh_in = self
+                    .refresh_every
+                    .map(|every| every.saturating_sub(self.last_sync.elapsed()));
+                let heal_in = self.healer.as_ref().map(|_| daemon::SIGNAL_CHECK_INTERVAL);
+                let wait = [refresh_in, resync_in, heal_in]
+                    .iter()
+                    .flatten()
+                    .fold(self.next_poll - Instant::now(), |wait, other| {
+                        std::cmp::min(wait, *other)
+                    });
+                daemon::sleep(wait);
+                None
+            }
+            None => {
+                let reason = self.poll(settings, daemon);
+                self.next_poll = Instant::now() + self.interval.current();
+                reason
+            }
+        }
+    }
+
+    fn poll(&mut self, settings: &Settings, daemon: &mut Daemon) -> Option<String> {
+        let changed = poll::changed_vaults(
+            settings,
+            &cache::load(&settings.export_path),
+            &mut self.reported,
+        );
+        match changed {
+            Ok(changed) if !changed.is_empty() => {
+                self.interval.changed();
+                daemon.failures = 0;
+                Some(format!("Vaults changed ({})", changed.join(", ")))
+            }
+            Ok(_) => {
+                self.interval.unchanged();
+                daemon.failures = 0;
+                None
+            }
+            Err(op::Error::Auth(e)) => {
+                daemon.sign_in.signed_out(&e);
+                let sign_in = &daemon.sign_in;
+                daemon.update(|status| sign_in.report(status));
+                None
+            }
+            Err(e) => {
+                eprintln!("Unable to check for changes: {}", e);
+                systemd::status(&format!("Unable to check for changes: {}", e));
+                self.interval.unchanged();
+                daemon.failures += 1;
+                None
+            }
+        }
+    }
+
+    /// Waits without looking for a reason to sync, dropping changes to the data folder
+    fn wait(&self, timeout: Duration) {
+        match &self.detector {
+            Some(active) => {
+                let _ = active.next_change(Some(timeout));
+            }
+            None => daemon::sleep(timeout),
+        }
+    }
+}
@@ -1,21 +1,106 @@
-pub fn write_file(path: std::path::PathBuf, contents: String) {
-    use std::fs::File;
+/// Writes the file by renaming a finished temporary file over it, so readers, and a sync aborted
+/// half way, never leave a partly written file behind.
+pub fn write_file(path: std::path::PathBuf, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    write_atomically(&path, contents.as_ref(), 0o666)
+}
+
+/// Writes a file only the current user can read, such as a key
+pub fn write_private_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomically(path, contents, 0o600)
+}
+
+fn write_atomically(path: &std::path::Path, contents: &[u8], mode: u32) -> std::io::Result<()> {
     use std::io::prelude::*;
-    use std::path::Path;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if let Some(folder) = path.parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.tmp-{}", name, std::process::id()));
+    // A temporary file left by an earlier process with the same ID is never written through
+    let _ = std::fs::remove_file(&temp_path);
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp_path)
+        .and_then(|mut file| file.write_all(contents))
+        .and_then(|_| std::fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    written
+}
+
+/// Binds a Unix socket only the current user can connect to. The umask is narrowed around `bind`
+/// as setting the permissions afterwards leaves a window in which anyone could connect.
+pub fn bind_private_socket(
+    path: &std::path::Path,
+) -> std::io::Result<std::os::unix::net::UnixListener> {
+    let umask = unsafe { libc::umask(0o077) };
+    let listener = std::os::unix::net::UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    listener
+}
 
-    let path = Path::new(&path);
-    let display = path.display();
+/// FNV-1a. Unlike `DefaultHasher` the result is stable across Rust releases, so it can be used in
+/// file names.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
 
-    let folder = path.parent().unwrap();
-    std::fs::create_dir_all(folder).unwrap();
+/// Longer than any timeout, interval or ttl needs to be, and short enough that adding it to the
+/// current time can't overflow
+const MAX_DURATION: u64 = 100 * 365 * 24 * 60 * 60;
 
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't create {}: {}", display, why),
-        Ok(file) => file,
+/// Parses a number of seconds with an optional `s`, `m`, `h`, `d` or `w` unit, such as `90m` or
+/// `7d`.
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 60 * 60),
+        Some((i, 'd')) => (&value[..i], 24 * 60 * 60),
+        Some((i, 'w')) => (&value[..i], 7 * 24 * 60 * 60),
+        _ => (value, 1),
     };
 
-    match file.write_all(contents.as_bytes()) {
-        Err(why) => panic!("couldn't write to {}: {}", display, why),
-        Ok(_) => {}
+    match number.trim().parse::<u64>() {
+        Ok(n) => match n.checked_mul(multiplier) {
+            Some(seconds) if seconds <= MAX_DURATION => Ok(seconds),
+            _ => Err(format!(
+                "duration {:?} is too long, the most is 100 years",
+                value
+            )),
+        },
+        Err(_) => Err(format!(
+            "invalid duration {:?}, expected a number of seconds or a value like 90m, 12h or 7d",
+            value
+        )),
+    }
+}
+
+/// `93784` becomes `1d 2h`. Only the two largest units are shown.
+pub fn format_duration(seconds: u64) -> String {
+    let units = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+    let mut parts = vec![];
+    let mut remaining = seconds;
+    for (unit, size) in units.iter() {
+        if remaining >= *size || (parts.is_empty() && *size == 1) {
+            parts.push(format!("{}{}", remaining / size, unit));
+            remaining %= size;
+        }
+        if parts.len() == 2 {
+            break;
+        }
     }
+    parts.join(" ")
 }
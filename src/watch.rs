@@ -0,0 +1,305 @@
+/// Detects writes to the 1Password database in either SQLite journal mode
+///
+/// In rollback journal mode SQLite creates `<db>-journal`, writes the database and then removes
+/// the journal. In WAL mode transactions are appended to `<db>-wal` and later checkpointed into
+/// the database, while `<db>-shm` is touched by readers as well as writers. Both produce bursts of
+/// events for a single change, so events are coalesced until the folder has been quiet for a
+/// while.
+use glob::Pattern;
+use notify::{op::Op, RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long the database must go without writes before a burst is considered finished
+const QUIET_PERIOD: Duration = Duration::from_secs(2);
+/// Bounds how long a steady stream of writes can hold off a sync
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Files that belong to a database named by a trigger
+const DATABASE_SUFFIXES: &[&str] = &["-journal", "-wal"];
+
+pub struct ChangeDetector {
+    // Events stop when the watcher is dropped
+    _watcher: RecommendedWatcher,
+    rx: Receiver<RawEvent>,
+    path: PathBuf,
+    triggers: Vec<Pattern>,
+    quiet_period: Duration,
+    max_delay: Duration,
+}
+
+impl ChangeDetector {
+    pub fn new(path: &Path, triggers: Vec<Pattern>) -> notify::Result<ChangeDetector> {
+        let (tx, rx) = channel();
+        let mut watcher = notify::raw_watcher(tx)?;
+        watcher.watch(path, RecursiveMode::Recursive)?;
+
+        Ok(ChangeDetector {
+            _watcher: watcher,
+            rx,
+            path: path.to_path_buf(),
+            triggers,
+            quiet_period: QUIET_PERIOD,
+            max_delay: MAX_DELAY,
+        })
+    }
+
+    #[cfg(test)]
+    fn with_timing(mut self, quiet_period: Duration, max_delay: Duration) -> ChangeDetector {
+        self.quiet_period = quiet_period;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Blocks until a burst of database writes has settled and returns the files involved.
+    /// Returns `None` if `timeout` passes without any database writes, and an error once the
+    /// watcher has stopped.
+    pub fn next_change(&self, timeout: Option<Duration>) -> notify::Result<Option<Vec<PathBuf>>> {
+        let started = Instant::now();
+        let mut changed: Vec<PathBuf> = vec![];
+        let mut first: Option<Instant> = None;
+        let mut last = started;
+
+        loop {
+            let now = Instant::now();
+            let wait = match first {
+                Some(first) => {
+                    let deadline = std::cmp::min(last + self.quiet_period, first + self.max_delay);
+                    if deadline <= now {
+                        return Ok(Some(changed));
+                    }
+                    Some(deadline - now)
+                }
+                None => match timeout {
+                    Some(timeout) if started + timeout <= now => return Ok(None),
+                    Some(timeout) => Some(started + timeout - now),
+                    None => None,
+                },
+            };
+
+            let event = match wait {
+                Some(wait) => match self.rx.recv_timeout(wait) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Err(disconnected()),
+                },
+                None => self.rx.recv().map_err(|_| disconnected())?,
+            };
+
+            let op = match event.op {
+                Ok(op) => op,
+                Err(e) => {
+                    eprintln!("watch error: {:?}", e);
+                    continue;
+                }
+            };
+            // A rescan means events were dropped, so anything may have changed. It usually comes
+            // without a path, as when the inotify queue overflows, so it's put down to the whole
+            // watched folder.
+            let (path, relevant) = match event.path {
+                _ if op.contains(Op::RESCAN) => (self.path.clone(), true),
+                Some(path) => {
+                    let relevant = is_database_write(op) && is_database_file(&path, &self.triggers);
+                    (path, relevant)
+                }
+                None => continue,
+            };
+            if relevant {
+                if first.is_none() {
+                    first = Some(Instant::now());
+                }
+                last = Instant::now();
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+        }
+    }
+}
+
+fn disconnected() -> notify::Error {
+    notify::Error::Generic("the file watcher stopped".to_string())
+}
+
+fn is_database_write(op: Op) -> bool {
+    op.intersects(Op::CREATE | Op::WRITE | Op::CLOSE_WRITE | Op::REMOVE | Op::RENAME)
+}
+
+/// True for a database named by a trigger, and its journal and WAL files. Shared memory files
+/// are left out as readers write to them too.
+pub fn is_database_file(path: &Path, triggers: &[Pattern]) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    let matches = |name: &str| triggers.iter().any(|t| t.matches(name));
+    matches(name)
+        || DATABASE_SUFFIXES
+            .iter()
+            .any(|suffix| matches!(name.strip_suffix(suffix), Some(db) if matches(db)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+
+    const NO_CHANGE_TIMEOUT: Duration = Duration::from_millis(800);
+    const CHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn detector(dir: &TempDir) -> ChangeDetector {
+        ChangeDetector::new(&dir.0, vec![Pattern::new("1password.sqlite").unwrap()])
+            .unwrap()
+            .with_timing(Duration::from_millis(300), Duration::from_secs(3))
+    }
+
+    fn append(path: &Path, bytes: &[u8]) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn rollback_journal_transaction_triggers_once() {
+        let dir = TempDir::new("watch");
+        append(&dir.file("1password.sqlite"), b"page");
+        let detector = detector(&dir);
+
+        append(&dir.file("1password.sqlite-journal"), b"original pages");
+        append(&dir.file("1password.sqlite"), b"new pages");
+        std::fs::remove_file(dir.file("1password.sqlite-journal")).unwrap();
+
+        let changed = detector.next_change(Some(CHANGE_TIMEOUT)).unwrap().unwrap();
+        assert!(changed.contains(&dir.file("1password.sqlite-journal")));
+        assert!(changed.contains(&dir.file("1password.sqlite")));
+        assert_eq!(detector.next_change(Some(NO_CHANGE_TIMEOUT)).unwrap(), None);
+    }
+
+    #[test]
+    fn wal_writes_and_checkpoint_trigger_once() {
+        let dir = TempDir::new("watch");
+        append(&dir.file("1password.sqlite"), b"page");
+        append(&dir.file("1password.sqlite-wal"), b"");
+        append(&dir.file("1password.sqlite-shm"), b"");
+        let detector = detector(&dir);
+
+        for _ in 0..5 {
+            append(&dir.file("1password.sqlite-shm"), b"index");
+            append(&dir.file("1password.sqlite-wal"), b"frame");
+        }
+        // Checkpoint: copy the frames into the database and reset the WAL
+        append(&dir.file("1password.sqlite"), b"frames");
+        File::create(dir.file("1password.sqlite-wal")).unwrap();
+
+        let changed = detector.next_change(Some(CHANGE_TIMEOUT)).unwrap().unwrap();
+        assert!(changed.contains(&dir.file("1password.sqlite-wal")));
+        assert!(!changed.contains(&dir.file("1password.sqlite-shm")));
+        assert_eq!(detector.next_change(Some(NO_CHANGE_TIMEOUT)).unwrap(), None);
+    }
+
+    #[test]
+    fn wal_file_created_and_removed_within_a_burst_triggers() {
+        let dir = TempDir::new("watch");
+        let detector = detector(&dir);
+
+        append(&dir.file("1password.sqlite-wal"), b"frame");
+        std::fs::remove_file(dir.file("1password.sqlite-wal")).unwrap();
+
+        assert!(detector
+            .next_change(Some(CHANGE_TIMEOUT))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn separate_bursts_trigger_separately() {
+        let dir = TempDir::new("watch");
+        let detector = detector(&dir);
+
+        append(&dir.file("1password.sqlite-wal"), b"frame");
+        assert!(detector
+            .next_change(Some(CHANGE_TIMEOUT))
+            .unwrap()
+            .is_some());
+
+        append(&dir.file("1password.sqlite-wal"), b"frame");
+        assert!(detector
+            .next_change(Some(CHANGE_TIMEOUT))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn steady_writes_trigger_after_max_delay() {
+        let dir = TempDir::new("watch");
+        let detector = detector(&dir);
+
+        let started = Instant::now();
+        let writer = {
+            let wal = dir.file("1password.sqlite-wal");
+            std::thread::spawn(move || {
+                for _ in 0..40 {
+                    append(&wal, b"frame");
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            })
+        };
+
+        assert!(detector
+            .next_change(Some(CHANGE_TIMEOUT))
+            .unwrap()
+            .is_some());
+        assert!(started.elapsed() < Duration::from_millis(3900));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn shared_memory_and_unrelated_files_are_ignored() {
+        let dir = TempDir::new("watch");
+        let detector = detector(&dir);
+
+        append(&dir.file("1password.sqlite-shm"), b"index");
+        append(&dir.file("settings.json"), b"{}");
+        append(&dir.file("other.sqlite-wal"), b"frame");
+        std::fs::remove_file(dir.file("settings.json")).unwrap();
+
+        assert_eq!(detector.next_change(Some(NO_CHANGE_TIMEOUT)).unwrap(), None);
+    }
+
+    #[test]
+    fn rescan_without_a_path_triggers() {
+        let dir = TempDir::new("watch");
+        let mut detector = detector(&dir);
+        let (tx, rx) = channel();
+        detector.rx = rx;
+
+        tx.send(RawEvent {
+            path: None,
+            op: Ok(Op::RESCAN),
+            cookie: None,
+        })
+        .unwrap();
+
+        let changed = detector.next_change(Some(CHANGE_TIMEOUT)).unwrap();
+        assert_eq!(changed, Some(vec![dir.0.clone()]));
+    }
+
+    #[test]
+    fn database_files_match_triggers_and_their_journals() {
+        let triggers = vec![Pattern::new("1password*.sqlite").unwrap()];
+        let is_db = |name: &str| is_database_file(Path::new(name), &triggers);
+
+        assert!(is_db("/data/1password.sqlite"));
+        assert!(is_db("/data/1password.sqlite-journal"));
+        assert!(is_db("/data/1password-beta.sqlite-wal"));
+        assert!(!is_db("/data/1password.sqlite-shm"));
+        assert!(!is_db("/data/other.sqlite-wal"));
+    }
+}